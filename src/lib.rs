@@ -4,43 +4,113 @@
     feature = "docsrs",
     cfg_attr(doc, doc = ::document_features::document_features!())
 )]
-#![forbid(unsafe_code)]
+#![cfg_attr(not(feature = "alloc-count"), forbid(unsafe_code))]
+#![cfg_attr(feature = "alloc-count", deny(unsafe_code))]
 #![warn(clippy::all, clippy::pedantic, clippy::nursery, clippy::cargo)]
 
+use std::borrow::Cow;
+#[cfg(feature = "alloc-count")]
+use std::cell::Cell;
+use std::cell::RefCell;
 use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::SystemTime;
 
 #[cfg(feature = "start-print-order")]
 use indexmap::IndexMap as HashMap;
 #[cfg(not(feature = "start-print-order"))]
 use std::collections::HashMap;
 
-#[cfg(not(feature = "minstant"))]
+#[cfg(not(any(feature = "minstant", feature = "quanta")))]
 use std::time::{Duration, Instant};
 #[cfg(feature = "minstant")]
 use ::{minstant::Instant, std::time::Duration};
+#[cfg(all(feature = "quanta", not(feature = "minstant")))]
+use ::{quanta::Instant, std::time::Duration};
 
-use tracing::Level;
+use tracing::{metadata::ParseLevelError, Level, Span};
+
+macro_rules! _span {
+    ($lvl:expr, $($args:tt)*) => {{
+        match $lvl {
+            Level::ERROR => ::tracing::span!(Level::ERROR, $($args)*),
+            Level::WARN  => ::tracing::span!(Level::WARN,  $($args)*),
+            Level::INFO  => ::tracing::span!(Level::INFO,  $($args)*),
+            Level::DEBUG => ::tracing::span!(Level::DEBUG, $($args)*),
+            Level::TRACE => ::tracing::span!(Level::TRACE, $($args)*),
+        }
+    }};
+}
+
+macro_rules! _event {
+    (target: $target:expr, $lvl:expr, $($args:tt)*) => {{
+        match $lvl {
+            Level::ERROR => ::tracing::event!(target: $target, Level::ERROR, $($args)*),
+            Level::WARN  => ::tracing::event!(target: $target, Level::WARN,  $($args)*),
+            Level::INFO  => ::tracing::event!(target: $target, Level::INFO,  $($args)*),
+            Level::DEBUG => ::tracing::event!(target: $target, Level::DEBUG, $($args)*),
+            Level::TRACE => ::tracing::event!(target: $target, Level::TRACE, $($args)*),
+        }
+    }};
+}
+
+macro_rules! _enabled {
+    (target: $target:expr, $lvl:expr) => {{
+        match $lvl {
+            Level::ERROR => ::tracing::enabled!(target: $target, Level::ERROR),
+            Level::WARN  => ::tracing::enabled!(target: $target, Level::WARN),
+            Level::INFO  => ::tracing::enabled!(target: $target, Level::INFO),
+            Level::DEBUG => ::tracing::enabled!(target: $target, Level::DEBUG),
+            Level::TRACE => ::tracing::enabled!(target: $target, Level::TRACE),
+        }
+    }};
+}
 
 /// Enum containing possible printing orders of total times.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 #[non_exhaustive]
 pub enum PrintOrder {
     /// Starting order.
-    #[cfg(feature = "start-print-order")]
     Start,
     /// Reverse starting order.
-    #[cfg(feature = "start-print-order")]
     RevStart,
     /// Key name.
     Key,
     /// Reverse key name.
     RevKey,
+    /// Key name, ignoring case.
+    KeyCi,
+    /// Reverse key name, ignoring case.
+    RevKeyCi,
+    /// Key name in natural order, so that `worker2` comes before `worker10`.
+    ///
+    /// Runs of ASCII digits are compared by their numeric value, leading zeros
+    /// aside, and other characters are compared as in `Key`. Keys that are still
+    /// equal, such as `a01` and `a1`, fall back to the `Key` order.
+    KeyNatural,
+    /// Reverse key name in natural order.
+    RevKeyNatural,
     /// Increasing duration.
     IncDuration,
     /// Decreasing duration.
     DecDuration,
+    /// Increasing percentage of the total time of the printed states.
+    ///
+    /// This orders like `IncDuration`, but states the intent of
+    /// listing the printed states by their share of the total.
+    Percent,
+    /// Decreasing percentage of the total time of the printed states.
+    RevPercent,
 }
 
+/// The default printing order depends on enabled features: `Start` with
+/// `start-print-order`, `DecDuration` otherwise.
+///
+/// Since features are unified across a build, another crate enabling
+/// `start-print-order` changes this default. Set the order explicitly with
+/// [`TimeReporterBuilder::new_ordered`] or [`TimeReporterBuilder::print_order`]
+/// if the output order must not depend on features.
 impl Default for PrintOrder {
     #[cfg(feature = "start-print-order")]
     fn default() -> Self {
@@ -52,25 +122,489 @@ impl Default for PrintOrder {
     }
 }
 
+impl PrintOrder {
+    fn cmp_states(self, a: &(&str, State), b: &(&str, State)) -> std::cmp::Ordering {
+        match self {
+            Self::Start => a.1.seq.cmp(&b.1.seq),
+            Self::RevStart => b.1.seq.cmp(&a.1.seq),
+            Self::Key => a.0.cmp(b.0),
+            Self::RevKey => b.0.cmp(a.0),
+            Self::KeyCi => cmp_ci(a.0, b.0),
+            Self::RevKeyCi => cmp_ci(b.0, a.0),
+            Self::KeyNatural => cmp_natural(a.0, b.0),
+            Self::RevKeyNatural => cmp_natural(b.0, a.0),
+            Self::IncDuration | Self::Percent => a.1.duration.cmp(&b.1.duration),
+            Self::DecDuration | Self::RevPercent => b.1.duration.cmp(&a.1.duration),
+        }
+    }
+}
+
+/// Enum containing possible rounding modes of the printed total times.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RoundingMode {
+    /// Round to the nearest digit.
+    #[default]
+    Nearest,
+    /// Round towards zero, i.e. truncate.
+    Down,
+    /// Round away from zero.
+    Up,
+}
+
+impl RoundingMode {
+    fn round(self, dur: Duration, precision: usize) -> Duration {
+        let exp = 9_usize.saturating_sub(precision);
+        if self == Self::Nearest || exp == 0 {
+            // Rounding to nearest is left to the formatter
+            return dur;
+        }
+        let unit = (0..exp).fold(1_u32, |unit, _| unit * 10);
+        let nanos = dur.subsec_nanos();
+        let rem = nanos % unit;
+        let down = Duration::new(dur.as_secs(), nanos - rem);
+        if self == Self::Up && rem != 0 {
+            down.saturating_add(Duration::from_nanos(unit.into()))
+        } else {
+            down
+        }
+    }
+
+    /// Like `round`, but also rounds to nearest instead of leaving it to the formatter.
+    fn round_exact(self, dur: Duration, precision: usize) -> Duration {
+        let exp = 9_usize.saturating_sub(precision);
+        if self != Self::Nearest || exp == 0 {
+            return self.round(dur, precision);
+        }
+        let half = (0..exp).fold(1_u32, |unit, _| unit * 10) / 2;
+        Self::Down.round(
+            dur.saturating_add(Duration::from_nanos(half.into())),
+            precision,
+        )
+    }
+}
+
+/// An error of the strict [`TimeReporter::try_start`] and [`TimeReporter::try_stop`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TimingError {
+    /// A state was started with an empty key.
+    EmptyKey,
+    /// The state being started was already being counted.
+    AlreadyRunning(&'static str),
+    /// No state was being counted.
+    NotRunning,
+}
+
+impl fmt::Display for TimingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::EmptyKey => f.write_str("state key is empty"),
+            Self::AlreadyRunning(key) => write!(f, "state {key:?} is already running"),
+            Self::NotRunning => f.write_str("no state is running"),
+        }
+    }
+}
+
+impl std::error::Error for TimingError {}
+
+/// A destination for time reports, see [`TimeReporter::report_to`].
+#[non_exhaustive]
+pub enum ReportSink {
+    /// Emit the report as a `tracing` event, as on `drop`.
+    Tracing,
+    /// Write the report as a line to a writer.
+    Writer(Box<dyn io::Write>),
+    /// Call a function with the report.
+    Callback(Box<dyn Fn(&str)>),
+}
+
+#[derive(Clone)]
+struct ReportHook(Arc<dyn Fn(&TimeReporter) + Send + Sync>);
+
+impl fmt::Debug for ReportHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ReportHook")
+    }
+}
+
+impl PartialEq for ReportHook {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for ReportHook {}
+
+/// The name, total time and count of a state, as compared by [`TimeReporterBuilder::sort_by`].
+type StatEntry = (&'static str, Duration, u64);
+
+/// A custom comparison of states, see [`TimeReporterBuilder::sort_by`].
+type StatsCompare = dyn Fn(&StatEntry, &StatEntry) -> std::cmp::Ordering + Send + Sync;
+
+#[derive(Clone)]
+struct StatsOrder(Arc<StatsCompare>);
+
+impl fmt::Debug for StatsOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("StatsOrder")
+    }
+}
+
+impl PartialEq for StatsOrder {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for StatsOrder {}
+
+/// The last report emitted, for [`TimeReporterBuilder::dedupe_reports`].
+///
+/// It is ignored when comparing reporters.
+#[derive(Debug, Default)]
+struct LastReport(Mutex<Option<String>>);
+
+impl Clone for LastReport {
+    fn clone(&self) -> Self {
+        let last = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        Self(Mutex::new(last.clone()))
+    }
+}
+
+impl PartialEq for LastReport {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for LastReport {}
+
+/// A regex filtering states, compared by its pattern so that the builder can be `Eq`.
+#[cfg(feature = "regex")]
+#[derive(Clone, Debug)]
+struct StateRegex(regex::Regex);
+
+#[cfg(feature = "regex")]
+impl PartialEq for StateRegex {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+#[cfg(feature = "regex")]
+impl Eq for StateRegex {}
+
+/// Numbers of allocations and allocated bytes.
+#[cfg(feature = "alloc-count")]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct AllocCount {
+    allocs: u64,
+    bytes: u64,
+}
+
+#[cfg(feature = "alloc-count")]
+impl AllocCount {
+    const ZERO: Self = Self {
+        allocs: 0,
+        bytes: 0,
+    };
+
+    /// Return the allocations counted by `CountingAlloc` on this thread so far.
+    fn current() -> Self {
+        THREAD_ALLOCS.try_with(Cell::get).unwrap_or(Self::ZERO)
+    }
+
+    const fn add(self, other: Self) -> Self {
+        Self {
+            allocs: self.allocs.saturating_add(other.allocs),
+            bytes: self.bytes.saturating_add(other.bytes),
+        }
+    }
+
+    const fn sub(self, other: Self) -> Self {
+        Self {
+            allocs: self.allocs.saturating_sub(other.allocs),
+            bytes: self.bytes.saturating_sub(other.bytes),
+        }
+    }
+}
+
+#[cfg(feature = "alloc-count")]
+thread_local! {
+    static THREAD_ALLOCS: Cell<AllocCount> = const { Cell::new(AllocCount::ZERO) };
+}
+
+/// A global allocator counting the allocations of each thread, so that a
+/// `TimeReporter` can attribute them to its states, see [`TimeReporter::allocs_for`]
+/// and [`TimeReporter::bytes_for`].
+///
+/// It wraps another allocator, the system one by default:
+///
+/// ```
+/// #[global_allocator]
+/// static ALLOC: tracing_perf::CountingAlloc = tracing_perf::CountingAlloc::system();
+/// ```
+///
+/// Reallocations are counted as allocations of their new size.
+#[cfg(feature = "alloc-count")]
+#[derive(Debug, Default)]
+pub struct CountingAlloc<A = std::alloc::System>(A);
+
+#[cfg(feature = "alloc-count")]
+impl CountingAlloc {
+    /// Create a new `CountingAlloc` wrapping the system allocator.
+    #[must_use]
+    pub const fn system() -> Self {
+        Self(std::alloc::System)
+    }
+}
+
+#[cfg(feature = "alloc-count")]
+impl<A> CountingAlloc<A> {
+    /// Create a new `CountingAlloc` wrapping the allocator `inner`.
+    pub const fn new(inner: A) -> Self {
+        Self(inner)
+    }
+
+    fn count(size: usize) {
+        let _ = THREAD_ALLOCS.try_with(|count| {
+            let bytes = u64::try_from(size).unwrap_or(u64::MAX);
+            count.set(count.get().add(AllocCount { allocs: 1, bytes }));
+        });
+    }
+}
+
+// SAFETY: all the allocator methods forward to the wrapped allocator,
+// only counting the allocations on the side, without allocating.
+#[cfg(feature = "alloc-count")]
+#[allow(unsafe_code)]
+unsafe impl<A: std::alloc::GlobalAlloc> std::alloc::GlobalAlloc for CountingAlloc<A> {
+    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+        Self::count(layout.size());
+        // SAFETY: the caller upholds the contract of `GlobalAlloc::alloc`
+        unsafe { self.0.alloc(layout) }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: std::alloc::Layout) -> *mut u8 {
+        Self::count(layout.size());
+        // SAFETY: the caller upholds the contract of `GlobalAlloc::alloc_zeroed`
+        unsafe { self.0.alloc_zeroed(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+        // SAFETY: the caller upholds the contract of `GlobalAlloc::dealloc`
+        unsafe { self.0.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: std::alloc::Layout, new_size: usize) -> *mut u8 {
+        Self::count(new_size);
+        // SAFETY: the caller upholds the contract of `GlobalAlloc::realloc`
+        unsafe { self.0.realloc(ptr, layout, new_size) }
+    }
+}
+
+/// A number of ticks per second, compared by its bits so that the builder can be `Eq`.
+#[derive(Copy, Clone, Debug)]
+struct TickRate(f64);
+
+impl PartialEq for TickRate {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.to_bits() == other.0.to_bits()
+    }
+}
+
+impl Eq for TickRate {}
+
+/// Total time gathered for a state.
+///
+/// States are compared without `max_end`, so that equal timelines compare equal
+/// whenever they were recorded.
+#[derive(Copy, Clone, Debug)]
+struct State {
+    duration: Duration,
+    /// Number of times the state was counted.
+    count: u64,
+    /// Sum of the squares of the time slices in nanoseconds, for the standard deviation.
+    sum_sq: u128,
+    /// Whether time was added without its time slices, so that `sum_sq` doesn't match it.
+    unsliced: bool,
+    /// Longest time slice, and when it ended.
+    max: Duration,
+    max_end: Option<Instant>,
+    /// Number of states started before this one, for printing by start order.
+    seq: usize,
+    #[cfg(feature = "alloc-count")]
+    allocs: AllocCount,
+}
+
+impl PartialEq for State {
+    fn eq(&self, other: &Self) -> bool {
+        #[cfg(feature = "alloc-count")]
+        if self.allocs != other.allocs {
+            return false;
+        }
+        self.duration == other.duration
+            && self.count == other.count
+            && self.sum_sq == other.sum_sq
+            && self.unsliced == other.unsliced
+            && self.max == other.max
+            && self.seq == other.seq
+    }
+}
+
+impl Eq for State {}
+
+impl State {
+    const fn new(seq: usize) -> Self {
+        Self {
+            duration: Duration::ZERO,
+            count: 0,
+            sum_sq: 0,
+            unsliced: false,
+            max: Duration::ZERO,
+            max_end: None,
+            seq,
+            #[cfg(feature = "alloc-count")]
+            allocs: AllocCount::ZERO,
+        }
+    }
+}
+
 /// A configurable builder for a `TimeReporter`
+///
+/// Setters return `&mut Self`, so a reporter can be built in a single expression:
+///
+/// ```
+/// use tracing::Level;
+/// use tracing_perf::TimeReporterBuilder;
+///
+/// let reporter = TimeReporterBuilder::new("x").level(Level::DEBUG).width(0).build();
+/// # reporter.finish();
+/// ```
+///
+/// To keep a configured builder, bind it first, as the temporary returned by
+/// `new` doesn't outlive the statement:
+///
+/// ```
+/// # use tracing::Level;
+/// # use tracing_perf::TimeReporterBuilder;
+/// let mut builder = TimeReporterBuilder::new("x");
+/// builder.level(Level::DEBUG).width(0);
+/// let (a, b) = (builder.build(), builder.build());
+/// # a.finish();
+/// # b.finish();
+/// ```
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct TimeReporterBuilder {
-    name: String,
+    name: Cow<'static, str>,
     level: Level,
     print_order: PrintOrder,
     width: usize,
     precision: usize,
+    per_state_events: bool,
+    summary: bool,
+    rounding: RoundingMode,
+    show_started_at: bool,
+    name_width: usize,
+    truncate_names: bool,
+    include_running: bool,
+    name_as_field: bool,
+    max_line_len: Option<usize>,
+    message_prefix: Cow<'static, str>,
+    #[cfg(feature = "log")]
+    log_backend: bool,
+    wrap_in_span: bool,
+    show_state_count: bool,
+    decimal_separator: char,
+    escalate: Option<(Duration, Level)>,
+    other_threshold: Option<Duration>,
+    coalesce_repeat_start: bool,
+    warn_unterminated: bool,
+    commit_running_on_drop: bool,
+    exact_durations: bool,
+    ticks_per_second: Option<TickRate>,
+    tick_unit: Cow<'static, str>,
+    kv_format: bool,
+    normalized: bool,
+    dedupe_reports: bool,
+    #[cfg(feature = "chrono")]
+    started_at_format: Option<Cow<'static, str>>,
+    include_only: Option<Vec<&'static str>>,
+    exclude: Vec<&'static str>,
+    #[cfg(feature = "regex")]
+    filter_regex: Option<StateRegex>,
+    secondary_order: Option<PrintOrder>,
+    compact: bool,
+    auto_stop_after: Option<Duration>,
+    show_thread: bool,
+    leader: char,
+    skip_empty: bool,
+    sort_by: Option<StatsOrder>,
+    iso8601: bool,
 }
 
 impl TimeReporterBuilder {
     /// Create a new `TimeReporter` builder with the given name
-    pub fn new<S: Into<String>>(name: S) -> Self {
+    pub fn new<S: Into<Cow<'static, str>>>(name: S) -> Self {
         Self {
             name: name.into(),
             level: Level::INFO,
             print_order: PrintOrder::default(),
             width: 11,
             precision: 9,
+            per_state_events: false,
+            summary: true,
+            rounding: RoundingMode::default(),
+            show_started_at: false,
+            name_width: 0,
+            truncate_names: false,
+            include_running: false,
+            name_as_field: false,
+            max_line_len: None,
+            message_prefix: Cow::Borrowed(""),
+            #[cfg(feature = "log")]
+            log_backend: false,
+            wrap_in_span: true,
+            show_state_count: false,
+            decimal_separator: '.',
+            escalate: None,
+            other_threshold: None,
+            coalesce_repeat_start: false,
+            warn_unterminated: false,
+            commit_running_on_drop: true,
+            exact_durations: false,
+            ticks_per_second: None,
+            tick_unit: "t".into(),
+            kv_format: false,
+            normalized: false,
+            dedupe_reports: false,
+            #[cfg(feature = "chrono")]
+            started_at_format: None,
+            include_only: None,
+            exclude: Vec::new(),
+            #[cfg(feature = "regex")]
+            filter_regex: None,
+            secondary_order: None,
+            compact: false,
+            auto_stop_after: None,
+            show_thread: false,
+            leader: ' ',
+            skip_empty: false,
+            sort_by: None,
+            iso8601: false,
+        }
+    }
+
+    /// Create a new `TimeReporter` builder with the given name and printing order.
+    ///
+    /// The printing order is then independent of the enabled features,
+    /// unlike [`PrintOrder::default`].
+    pub fn new_ordered<S: Into<Cow<'static, str>>>(name: S, print_order: PrintOrder) -> Self {
+        Self {
+            print_order,
+            ..Self::new(name)
         }
     }
 
@@ -78,23 +612,40 @@ impl TimeReporterBuilder {
     pub fn build(&self) -> TimeReporter {
         TimeReporter {
             times: HashMap::new(),
-            name: self.name.clone(),
             cur_state_time: None,
-            level: self.level,
-            print_order: self.print_order,
-            width: self.width,
-            precision: self.precision,
+            created_at: SystemTime::now(),
+            created: Instant::now(),
+            on_report: None,
+            tags: HashMap::new(),
+            suppress_report: false,
+            saturated: false,
+            last_report: LastReport::default(),
+            #[cfg(feature = "alloc-count")]
+            allocs_mark: AllocCount::ZERO,
+            depth: 0,
+            max_depth: 0,
+            phase: 0,
+            config: self.clone(),
         }
     }
 
     /// Set the logging level.
-    pub fn level(&mut self, level: Level) -> &mut Self {
+    pub const fn level(&mut self, level: Level) -> &mut Self {
         self.level = level;
         self
     }
 
+    /// Set the logging level from its name, such as `"debug"`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `level` is not a valid level name or number.
+    pub fn level_str(&mut self, level: &str) -> Result<&mut Self, ParseLevelError> {
+        Ok(self.level(level.parse()?))
+    }
+
     /// Set the printing order of the total times.
-    pub fn print_order(&mut self, print_order: PrintOrder) -> &mut Self {
+    pub const fn print_order(&mut self, print_order: PrintOrder) -> &mut Self {
         self.print_order = print_order;
         self
     }
@@ -103,161 +654,2582 @@ impl TimeReporterBuilder {
     ///
     /// Note: Should be at least `precision + 2`  (i.e at least one leading digit + decimal point + precision)
     /// or this option will have no effect. Alternatively precision should be at most `width - 2`.
+    /// In debug builds, a warning is emitted on the first report if this is not the case.
     ///
     /// Fill character and alignment are hardcoded to space and left-align.
-    pub fn width(&mut self, width: usize) -> &mut Self {
+    pub const fn width(&mut self, width: usize) -> &mut Self {
         self.width = width;
         self
     }
 
     /// Set the number of digits after the decimal point
     /// that should be printed for the total times.
-    pub fn precision(&mut self, precision: usize) -> &mut Self {
+    ///
+    /// Total times are converted to `f64` seconds for printing, which only keeps all
+    /// 9 digits up to about 104 days (2^53 nanoseconds). Beyond that, the last digits
+    /// are lost; use [`exact_durations`](Self::exact_durations) to avoid this.
+    pub const fn precision(&mut self, precision: usize) -> &mut Self {
         self.precision = precision;
         self
     }
-}
 
-/// Collect and report total time spent on set of activities.
-///
-/// `TimeReporter` is useful for generating and reporting
-/// time reports: how much time was spend on a given activity.
-///
-/// On `drop` or on call to `finish` it will report total times
-/// gathered as a `tracing` event.
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub struct TimeReporter {
-    times: HashMap<&'static str, Duration>,
-    cur_state_time: Option<(&'static str, Instant)>,
-    name: String,
-    level: Level,
-    print_order: PrintOrder,
-    width: usize,
-    precision: usize,
-}
+    /// Set whether to emit an event for each state, besides the summary message.
+    ///
+    /// Each event has a `state` field with the state name and a `duration_secs`
+    /// field with its total time in seconds, so subscribers can filter per state.
+    pub const fn per_state_events(&mut self, per_state_events: bool) -> &mut Self {
+        self.per_state_events = per_state_events;
+        self
+    }
 
-impl TimeReporter {
-    /// Create a new `TimeReporter`.
-    pub fn new<S: Into<String>>(name: S) -> Self {
-        TimeReporterBuilder::new(name).build()
+    /// Set whether to emit the summary message with all total times.
+    ///
+    /// Enabled by default.
+    pub const fn summary(&mut self, summary: bool) -> &mut Self {
+        self.summary = summary;
+        self
     }
 
-    /// Create a new `TimeReporter` with a specified level.
-    pub fn new_with_level<S: Into<String>>(name: S, level: Level) -> Self {
-        TimeReporterBuilder::new(name).level(level).build()
+    /// Set how the total times are rounded to the configured precision.
+    ///
+    /// Defaults to [`RoundingMode::Nearest`].
+    pub const fn rounding(&mut self, rounding: RoundingMode) -> &mut Self {
+        self.rounding = rounding;
+        self
     }
 
-    /// Start counting time for a state named "key".
+    /// Set whether to include the time the reporter was created in the summary message.
     ///
-    /// If this `TimeReporter` was already counting time
-    /// for another state, it will end counting time for it
-    /// before starting a new one.
-    pub fn start(&mut self, key: &'static str) {
-        let now = Instant::now();
-        self.save_current(now);
-        self.cur_state_time = Some((key, now));
+    /// It is attached as a `started_at` field, in seconds since the Unix epoch,
+    /// or formatted as set with `started_at_format` if the `chrono` feature is enabled.
+    pub const fn show_started_at(&mut self, show_started_at: bool) -> &mut Self {
+        self.show_started_at = show_started_at;
+        self
     }
 
-    /// Start counting time and execute a function `f`.
+    /// Set the minimum formatting width of the state names, to align the total times.
     ///
-    /// This is handy syntax for `if let` or `while let` expressions
-    /// where it would be inconvenient to add a standalone `start` call.
-    pub fn start_with<F, R>(&mut self, key: &'static str, f: F) -> R
-    where
-        F: FnOnce() -> R,
-    {
-        self.start(key);
+    /// Names are left-aligned and padded with the [`leader`](Self::leader) character.
+    pub const fn name_width(&mut self, name_width: usize) -> &mut Self {
+        self.name_width = name_width;
+        self
+    }
 
-        f()
+    /// Set whether to truncate state names longer than the [`name_width`](Self::name_width),
+    /// replacing their end with an ellipsis.
+    pub const fn truncate_names(&mut self, truncate_names: bool) -> &mut Self {
+        self.truncate_names = truncate_names;
+        self
     }
 
-    fn save_current(&mut self, now: Instant) {
-        if let Some((key, prev)) = self.cur_state_time.take() {
-            *self.times.entry(key).or_insert_with(|| Duration::new(0, 0)) += now - prev;
-        }
+    /// Set whether to include the time of the currently running state
+    /// in [`stats`](TimeReporter::stats) and the printed total times.
+    ///
+    /// Note that formatting then reads the clock, so the output may differ between calls.
+    pub const fn include_running(&mut self, include_running: bool) -> &mut Self {
+        self.include_running = include_running;
+        self
     }
 
-    /// Stop counting time.
-    pub fn stop(&mut self) {
-        let now = Instant::now();
-        self.save_current(now);
+    /// Set whether to attach the reporter name as a `name` field of the `time-report` span,
+    /// instead of printing it with the total times.
+    pub const fn name_as_field(&mut self, name_as_field: bool) -> &mut Self {
+        self.name_as_field = name_as_field;
+        self
     }
 
-    /// Finish counting time and report results.
-    #[allow(clippy::unused_self)]
-    pub fn finish(self) {}
-}
+    /// Set the maximum length in bytes of the printed report.
+    ///
+    /// States that do not fit, in printing order, are left out and counted
+    /// in a final `…(+N more)` entry instead, so no number is cut in the middle.
+    pub const fn max_line_len(&mut self, max_line_len: usize) -> &mut Self {
+        self.max_line_len = Some(max_line_len);
+        self
+    }
 
-fn get_times(
-    times: &HashMap<&'static str, Duration>,
-    print_order: PrintOrder,
-) -> Vec<(&'static str, Duration)> {
-    cfg_if::cfg_if! {
-        if #[cfg(feature = "start-print-order")] {
-            if print_order == PrintOrder::RevStart {
-                times.iter().rev().map(|(&k, &v)| (k, v)).collect()
-            } else {
-                times.iter().map(|(&k, &v)| (k, v)).collect()
-            }
-        } else {
-            let _ = print_order;
-            times.iter().map(|(&k, &v)| (k, v)).collect()
-        }
+    /// Set a prefix for the summary message, e.g. `"[profile] "` to make reports easy to grep.
+    ///
+    /// It is prepended as is to the formatted total times, including by
+    /// [`report_to`](TimeReporter::report_to) and
+    /// [`finish_to_string`](TimeReporter::finish_to_string). It counts towards the
+    /// [`max_line_len`](Self::max_line_len).
+    pub fn message_prefix<S: Into<Cow<'static, str>>>(&mut self, message_prefix: S) -> &mut Self {
+        self.message_prefix = message_prefix.into();
+        self
     }
-}
 
-impl<'a> fmt::Display for TimeReporter {
-    #[allow(clippy::cast_precision_loss)]
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut stats: Vec<(&'static str, Duration)> = get_times(&self.times, self.print_order);
-        match self.print_order {
-            #[cfg(feature = "start-print-order")]
-            PrintOrder::Start | PrintOrder::RevStart => {}
-            PrintOrder::Key => stats.sort_by_key(|s| s.0),
-            PrintOrder::RevKey => stats.sort_by(|a, b| b.0.cmp(a.0)),
-            PrintOrder::IncDuration => stats.sort_by_key(|s| s.1),
-            PrintOrder::DecDuration => stats.sort_by(|a, b| b.1.cmp(&a.1)),
-        }
+    /// Set whether to report through the [`log`](https://docs.rs/log/latest/log/) crate
+    /// instead of `tracing`.
+    ///
+    /// Only the summary message is logged, with the `tracing-perf` target.
+    #[cfg(feature = "log")]
+    pub const fn log_backend(&mut self, log_backend: bool) -> &mut Self {
+        self.log_backend = log_backend;
+        self
+    }
 
-        write!(f, "name: {}", self.name)?;
-        let precision = self.precision;
-        let width = self.width;
-        for &(state, dur) in &stats {
-            let dur = dur.as_secs() as f64 + f64::from(dur.subsec_nanos()) / 1_000_000_000_f64;
-            write!(f, ", {}: {:<width$.precision$}", state, dur)?;
-        }
+    /// Set whether to emit the report events within a `time-report` span.
+    ///
+    /// Enabled by default. When disabled, the fields otherwise attached to
+    /// the span, such as `name`, are attached to each event instead.
+    pub const fn wrap_in_span(&mut self, wrap_in_span: bool) -> &mut Self {
+        self.wrap_in_span = wrap_in_span;
+        self
+    }
 
-        Ok(())
+    /// Set whether to attach the number of distinct states as a `states` field of the summary message.
+    pub const fn show_state_count(&mut self, show_state_count: bool) -> &mut Self {
+        self.show_state_count = show_state_count;
+        self
     }
-}
 
-macro_rules! _span {
-    ($lvl:expr, $($args:tt)*) => {{
-        match $lvl {
-            Level::ERROR => ::tracing::span!(Level::ERROR, $($args)*),
-            Level::WARN  => ::tracing::span!(Level::WARN,  $($args)*),
-            Level::INFO  => ::tracing::span!(Level::INFO,  $($args)*),
-            Level::DEBUG => ::tracing::span!(Level::DEBUG, $($args)*),
-            Level::TRACE => ::tracing::span!(Level::TRACE, $($args)*),
+    /// Set the character separating the integer and fractional parts of durations in the report.
+    ///
+    /// Defaults to `.`; `,` suits spreadsheets in many European locales.
+    pub const fn decimal_separator(&mut self, decimal_separator: char) -> &mut Self {
+        self.decimal_separator = decimal_separator;
+        self
+    }
+
+    /// Emit the report at `level` instead of the configured level when the
+    /// [total time](TimeReporter::total) of all states exceeds `threshold`,
+    /// so that slow runs stand out.
+    ///
+    /// The more severe of the two levels is used.
+    pub const fn escalate_if(&mut self, threshold: Duration, level: Level) -> &mut Self {
+        self.escalate = Some((threshold, level));
+        self
+    }
+
+    /// Fold the states whose total time is below `threshold` into a single
+    /// `<other>` entry, so that the report stays focused while the total time
+    /// of all entries is kept.
+    ///
+    /// The entry is sorted with the other states. By start order, it takes
+    /// the place of the first state folded into it. A state named `<other>`
+    /// is always folded into it, so that the name is only listed once.
+    pub const fn other_threshold(&mut self, threshold: Duration) -> &mut Self {
+        self.other_threshold = Some(threshold);
+        self
+    }
+
+    /// Set whether starting the state that is already being counted continues it,
+    /// instead of ending its time slice and starting a new one.
+    ///
+    /// This keeps the counts, and so the averages, per actual call.
+    pub const fn coalesce_repeat_start(&mut self, coalesce_repeat_start: bool) -> &mut Self {
+        self.coalesce_repeat_start = coalesce_repeat_start;
+        self
+    }
+
+    /// Set whether to emit a `WARN` event naming the state that is still being
+    /// counted when the `TimeReporter` is dropped, to catch missing `stop` calls.
+    pub const fn warn_unterminated(&mut self, warn_unterminated: bool) -> &mut Self {
+        self.warn_unterminated = warn_unterminated;
+        self
+    }
+
+    /// Set whether the state that is still being counted when the `TimeReporter`
+    /// is dropped is stopped and counted, which is the default.
+    ///
+    /// If disabled, its last time slice is ignored, so that only explicitly
+    /// stopped time is counted.
+    pub const fn commit_running_on_drop(&mut self, commit_running_on_drop: bool) -> &mut Self {
+        self.commit_running_on_drop = commit_running_on_drop;
+        self
+    }
+
+    /// Set whether durations are printed exactly from their seconds and nanoseconds,
+    /// instead of through an `f64`, which can't represent every duration exactly.
+    ///
+    /// This makes reports reproducible byte for byte, and keeps nanoseconds exact
+    /// for total times too long for an `f64`, see [`precision`](Self::precision).
+    pub const fn exact_durations(&mut self, exact_durations: bool) -> &mut Self {
+        self.exact_durations = exact_durations;
+        self
+    }
+
+    /// Print durations as a number of ticks, e.g. frames, at `ticks_per_second`
+    /// instead of seconds, followed by the [tick unit](Self::tick_unit).
+    pub const fn ticks_per_second(&mut self, ticks_per_second: f64) -> &mut Self {
+        self.ticks_per_second = Some(TickRate(ticks_per_second));
+        self
+    }
+
+    /// Set the unit printed after durations given in ticks, `t` by default.
+    pub fn tick_unit<S: Into<Cow<'static, str>>>(&mut self, tick_unit: S) -> &mut Self {
+        self.tick_unit = tick_unit.into();
+        self
+    }
+
+    /// Set whether the report is formatted as space-separated `key=value` pairs,
+    /// e.g. `name=db state_a=0.12 state_b=0.34`, for simple log scraping.
+    ///
+    /// Whitespace and `=` in the keys are replaced with `_`.
+    pub const fn kv_format(&mut self, kv_format: bool) -> &mut Self {
+        self.kv_format = kv_format;
+        self
+    }
+
+    /// Set whether states are printed as a fraction of the longest total time,
+    /// from 0 to 1, instead of as durations.
+    ///
+    /// This helps comparing the shape of profiles whose absolute times vary between runs.
+    pub const fn normalized(&mut self, normalized: bool) -> &mut Self {
+        self.normalized = normalized;
+        self
+    }
+
+    /// Set whether a report identical to the previous one of the same `TimeReporter`
+    /// is skipped, to avoid repeating reports while nothing changes.
+    pub const fn dedupe_reports(&mut self, dedupe_reports: bool) -> &mut Self {
+        self.dedupe_reports = dedupe_reports;
+        self
+    }
+
+    /// Set a [`chrono` format string](https://docs.rs/chrono/latest/chrono/format/strftime/index.html)
+    /// for the `started_at` field of [`show_started_at`](Self::show_started_at),
+    /// e.g. `%+` for RFC 3339, instead of seconds since the Unix epoch.
+    ///
+    /// The time is formatted in UTC. An invalid format string falls back to RFC 3339.
+    #[cfg(feature = "chrono")]
+    pub fn started_at_format<S: Into<Cow<'static, str>>>(&mut self, format: S) -> &mut Self {
+        self.started_at_format = Some(format.into());
+        self
+    }
+
+    /// Only report the states named in `keys`.
+    ///
+    /// If [`exclude`](Self::exclude) is also set, the states it names are
+    /// left out even if listed here.
+    pub fn include_only(&mut self, keys: Vec<&'static str>) -> &mut Self {
+        self.include_only = Some(keys);
+        self
+    }
+
+    /// Leave the states named in `keys` out of the report.
+    ///
+    /// This takes precedence over [`include_only`](Self::include_only).
+    pub fn exclude(&mut self, keys: Vec<&'static str>) -> &mut Self {
+        self.exclude = keys;
+        self
+    }
+
+    /// Only report the states whose name matches `regex`, e.g. `^db\.` for database states.
+    ///
+    /// It is applied along with [`include_only`](Self::include_only) and
+    /// [`exclude`](Self::exclude): a state must pass all of them to be reported.
+    #[cfg(feature = "regex")]
+    pub fn filter_regex(&mut self, regex: regex::Regex) -> &mut Self {
+        self.filter_regex = Some(StateRegex(regex));
+        self
+    }
+
+    /// Set the order of states that are equal in the [printing order](Self::print_order),
+    /// e.g. to list states with the same total time by key name.
+    pub const fn secondary_order(&mut self, secondary_order: PrintOrder) -> &mut Self {
+        self.secondary_order = Some(secondary_order);
+        self
+    }
+
+    /// Set whether the report is formatted compactly, as `state:secs` tokens
+    /// separated by commas, without any padding, e.g. `name:db,a:0.12,b:0.34`.
+    ///
+    /// Whitespace and `:` in the names are replaced with `_`. The tokens are separated
+    /// by `;` instead if the [`decimal_separator`](Self::decimal_separator) is `,`.
+    /// [`kv_format`](Self::kv_format) takes precedence if both are enabled.
+    pub const fn compact(&mut self, compact: bool) -> &mut Self {
+        self.compact = compact;
+        self
+    }
+
+    /// Cap each time slice at `limit`, counting the excess in an `<idle>` state.
+    ///
+    /// This is a heuristic for a forgotten `stop`: without it, a state started
+    /// last before a long idle period is counted until the next `start` or the
+    /// end of the reporter. It is applied to every time slice when it ends,
+    /// including those ended by an explicit `stop`. The running slice shown with
+    /// [`include_running`](Self::include_running) is capped too, but its excess
+    /// is only counted as `<idle>` once it ends. The excess is counted together
+    /// with any state started as `<idle>`.
+    pub const fn auto_stop_after(&mut self, limit: Duration) -> &mut Self {
+        self.auto_stop_after = Some(limit);
+        self
+    }
+
+    /// Set whether to attach the name of the reporting thread, or its id if unnamed,
+    /// as a `thread` field of the summary message.
+    pub const fn show_thread(&mut self, show_thread: bool) -> &mut Self {
+        self.show_thread = show_thread;
+        self
+    }
+
+    /// Set the character filling the gap between names padded to the
+    /// [name width](Self::name_width) and their total times, e.g. `.` for
+    /// `state.....: 0.123`. Defaults to a space.
+    pub const fn leader(&mut self, leader: char) -> &mut Self {
+        self.leader = leader;
+        self
+    }
+
+    /// Set whether a `TimeReporter` for which no time was gathered skips its report on `drop`.
+    pub const fn skip_empty(&mut self, skip_empty: bool) -> &mut Self {
+        self.skip_empty = skip_empty;
+        self
+    }
+
+    /// Sort the states with `compare`, given the name, total time and count of two states,
+    /// e.g. to sort them by their average time.
+    ///
+    /// This takes precedence over [`print_order`](Self::print_order) and
+    /// [`secondary_order`](Self::secondary_order).
+    pub fn sort_by<F>(&mut self, compare: F) -> &mut Self
+    where
+        F: Fn(&(&'static str, Duration, u64), &(&'static str, Duration, u64)) -> std::cmp::Ordering
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.sort_by = Some(StatsOrder(Arc::new(compare)));
+        self
+    }
+
+    /// Set whether durations are printed as ISO 8601 durations in seconds, e.g. `PT1.5S`,
+    /// with up to [`precision`](Self::precision) digits and without trailing zeros.
+    pub const fn iso8601(&mut self, iso8601: bool) -> &mut Self {
+        self.iso8601 = iso8601;
+        self
+    }
+}
+
+/// Collect and report total time spent on set of activities.
+///
+/// `TimeReporter` is useful for generating and reporting
+/// time reports: how much time was spend on a given activity.
+///
+/// On `drop` or on call to `finish` it will report total times
+/// gathered as a `tracing` event. A state that is still being counted
+/// at that point is stopped first, so its last time slice isn't lost,
+/// see [`TimeReporterBuilder::commit_running_on_drop`].
+///
+/// If it is dropped while the thread is panicking, the report is still
+/// emitted, but its `time-report` span gets a `panicked = true` field
+/// so it can be told apart from a normal completion.
+///
+/// Two reporters are equal if they recorded the same times with the same
+/// configuration, regardless of when they were created.
+#[derive(Clone, Debug)]
+pub struct TimeReporter {
+    times: HashMap<&'static str, State>,
+    cur_state_time: Option<(&'static str, Instant)>,
+    created_at: SystemTime,
+    created: Instant,
+    on_report: Option<ReportHook>,
+    tags: HashMap<&'static str, Vec<(&'static str, &'static str)>>,
+    suppress_report: bool,
+    saturated: bool,
+    last_report: LastReport,
+    #[cfg(feature = "alloc-count")]
+    allocs_mark: AllocCount,
+    depth: usize,
+    max_depth: usize,
+    phase: usize,
+    config: TimeReporterBuilder,
+}
+
+impl PartialEq for TimeReporter {
+    fn eq(&self, other: &Self) -> bool {
+        #[cfg(feature = "alloc-count")]
+        if self.allocs_mark != other.allocs_mark {
+            return false;
+        }
+        self.times == other.times
+            && self.cur_state_time == other.cur_state_time
+            && self.on_report == other.on_report
+            && self.tags == other.tags
+            && self.suppress_report == other.suppress_report
+            && self.saturated == other.saturated
+            && self.last_report == other.last_report
+            && self.depth == other.depth
+            && self.max_depth == other.max_depth
+            && self.phase == other.phase
+            && self.config == other.config
+    }
+}
+
+impl Eq for TimeReporter {}
+
+impl TimeReporter {
+    /// Create a new `TimeReporter`.
+    pub fn new<S: Into<Cow<'static, str>>>(name: S) -> Self {
+        TimeReporterBuilder::new(name).build()
+    }
+
+    /// Create a new `TimeReporter` with a specified level.
+    pub fn new_with_level<S: Into<Cow<'static, str>>>(name: S, level: Level) -> Self {
+        TimeReporterBuilder::new(name).level(level).build()
+    }
+
+    /// Return the time at which this `TimeReporter` was created.
+    #[must_use]
+    pub const fn created_at(&self) -> SystemTime {
+        self.created_at
+    }
+
+    /// Return the time elapsed since this `TimeReporter` was created.
+    ///
+    /// Unlike [`total`](Self::total), this includes the time no state was counted.
+    #[must_use]
+    pub fn lifetime(&self) -> Duration {
+        self.created.elapsed()
+    }
+
+    /// Return the fraction of the [lifetime](Self::lifetime) of this `TimeReporter`
+    /// during which a state was counted, including the current one.
+    ///
+    /// It can exceed 1 if times of other reporters were merged.
+    #[must_use]
+    pub fn coverage(&self) -> f64 {
+        let lifetime = self.lifetime();
+        if lifetime.is_zero() {
+            return 0.0;
+        }
+        let running = self
+            .cur_state_time
+            .map_or(Duration::ZERO, |(_, at)| at.elapsed());
+        self.total()
+            .saturating_add(running)
+            .div_duration_f64(lifetime)
+    }
+
+    /// Start counting time for a state named "key".
+    ///
+    /// If this `TimeReporter` was already counting time
+    /// for another state, it will end counting time for it
+    /// before starting a new one.
+    ///
+    /// An empty key is replaced with `"<unnamed>"`,
+    /// so the state does not show up as a blank in reports.
+    /// Its time is counted together with any state started as `<unnamed>`.
+    pub fn start(&mut self, key: &'static str) {
+        self.start_at(key, Instant::now());
+    }
+
+    /// Start counting time for a state named "key" at the instant `at`.
+    ///
+    /// Like [`start`](Self::start), but the time counted for the previous
+    /// state ends at `at` instead of now. This allows rebuilding a timeline
+    /// from recorded timestamps.
+    ///
+    /// The caller is responsible for passing instants in monotonic order:
+    /// an instant earlier than the start of the current state counts as zero time.
+    pub fn start_at(&mut self, key: &'static str, at: Instant) {
+        let key = if key.is_empty() { "<unnamed>" } else { key };
+        if self.config.coalesce_repeat_start
+            && self.cur_state_time.is_some_and(|(cur, _)| cur == key)
+        {
+            return;
+        }
+        self.save_current(at);
+        self.cur_state_time = Some((key, at));
+        #[cfg(feature = "alloc-count")]
+        {
+            self.allocs_mark = AllocCount::current();
+        }
+    }
+
+    /// Start counting time for a state named "key", like [`start`](Self::start),
+    /// but fail instead of fixing up a likely instrumentation bug.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`TimingError::EmptyKey`] if "key" is empty, and
+    /// [`TimingError::AlreadyRunning`] if "key" is the current state.
+    pub fn try_start(&mut self, key: &'static str) -> Result<(), TimingError> {
+        if key.is_empty() {
+            return Err(TimingError::EmptyKey);
+        }
+        if self.cur_state_time.is_some_and(|(cur, _)| cur == key) {
+            return Err(TimingError::AlreadyRunning(key));
+        }
+        self.start(key);
+        Ok(())
+    }
+
+    /// Stop counting time, like [`stop`](Self::stop), and return the time slice
+    /// just counted, but fail if no state was being counted.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`TimingError::NotRunning`] if no state was being counted.
+    pub fn try_stop(&mut self) -> Result<Duration, TimingError> {
+        self.stop_at(Instant::now()).ok_or(TimingError::NotRunning)
+    }
+
+    /// Start counting time for the phase named "name" of a sequence of steps.
+    ///
+    /// This is the same as [`start`](Self::start), to name a step of
+    /// [`next_phase`](Self::next_phase) explicitly.
+    pub fn phase(&mut self, name: &'static str) {
+        self.start(name);
+    }
+
+    /// Start counting time for the next step of a sequence, in a state named
+    /// `phase N`, where N counts the calls from 1.
+    ///
+    /// The phases sort in order with [`PrintOrder::KeyNatural`].
+    /// Each distinct phase name is allocated once and kept for the rest of the program.
+    pub fn next_phase(&mut self) {
+        self.phase += 1;
+        let key = intern(format!("phase {}", self.phase));
+        self.start(key);
+    }
+
+    /// Start counting time for a state named "key" if `cond` is true,
+    /// otherwise keep counting time for the current state.
+    pub fn start_if(&mut self, cond: bool, key: &'static str) {
+        if cond {
+            self.start(key);
+        }
+    }
+
+    /// Start counting time and execute a function `f`.
+    ///
+    /// This is handy syntax for `if let` or `while let` expressions
+    /// where it would be inconvenient to add a standalone `start` call.
+    pub fn start_with<F, R>(&mut self, key: &'static str, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        self.start(key);
+
+        f()
+    }
+
+    /// Execute each of `steps` in order, counting time for it under its key,
+    /// and return their results.
+    ///
+    /// The state that was running before, if any, is started again afterwards.
+    pub fn measure_all<'a, R, I>(&mut self, steps: I) -> Vec<R>
+    where
+        I: IntoIterator<Item = (&'static str, Box<dyn FnOnce() -> R + 'a>)>,
+    {
+        let prev = self.cur_state_time.map(|(key, _)| key);
+        let results = steps
+            .into_iter()
+            .map(|(key, step)| self.start_with(key, step))
+            .collect();
+        match prev {
+            Some(key) => self.start(key),
+            None => self.stop(),
+        }
+        results
+    }
+
+    /// Start counting time for a state named "key" until the returned guard is dropped.
+    ///
+    /// Dropping the guard starts the state that was running before again, if any,
+    /// so scopes can be nested by calling `scope` on the guard.
+    /// The deepest nesting reached is returned by [`max_depth`](Self::max_depth).
+    pub fn scope(&mut self, key: &'static str) -> Scope<'_> {
+        let prev = self.cur_state_time.map(|(key, _)| key);
+        self.start(key);
+        self.depth += 1;
+        self.max_depth = self.max_depth.max(self.depth);
+        Scope {
+            reporter: self,
+            prev,
+        }
+    }
+
+    /// Return a child reporter whose total time is added to the state named "key"
+    /// of this reporter when the child is dropped, to build a call-tree profile.
+    ///
+    /// The child has the configuration of this reporter and is named "key".
+    /// Its report isn't emitted: when it is dropped, the state still being
+    /// counted is stopped, and its total time is counted as a single time slice
+    /// of "key". Enable [`breakdown`](ChildReporter::breakdown) to also merge its
+    /// states, prefixed with "key" and `::`.
+    ///
+    /// The current state of this reporter is stopped meanwhile, and started again
+    /// when the child is dropped, so the time isn't counted twice.
+    pub fn child(&mut self, key: &'static str) -> ChildReporter<'_> {
+        let prev = self.cur_state_time.map(|(key, _)| key);
+        self.stop();
+        let mut builder = self.to_builder();
+        builder.name = key.into();
+        let mut reporter = builder.build();
+        reporter.suppress_report = true;
+        ChildReporter {
+            parent: self,
+            key,
+            prev,
+            reporter,
+            breakdown: false,
+        }
+    }
+
+    /// Return the deepest nesting of [scopes](Self::scope) reached so far.
+    ///
+    /// This helps detect unexpected recursion in instrumented code.
+    #[must_use]
+    pub const fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// Attach a `k=v` tag to the state named "key", replacing any previous value of `k`.
+    ///
+    /// Tags are included in the [per-state events](TimeReporterBuilder::per_state_events)
+    /// as a `tags` field, formatted as comma-separated `k=v` pairs, for grouping and
+    /// filtering in structured logging backends. They are kept by [`clear`](Self::clear).
+    pub fn tag(&mut self, key: &'static str, k: &'static str, v: &'static str) {
+        let tags = self.tags.entry(key).or_default();
+        match tags.iter_mut().find(|tag| tag.0 == k) {
+            Some(tag) => tag.1 = v,
+            None => tags.push((k, v)),
+        }
+    }
+
+    /// Count the time slice of the current state, if any, up to `now`,
+    /// and return its counted part.
+    fn save_current(&mut self, now: Instant) -> Option<Duration> {
+        let (key, prev) = self.cur_state_time.take()?;
+        let elapsed = now.saturating_duration_since(prev);
+        let slice = self
+            .config
+            .auto_stop_after
+            .map_or(elapsed, |limit| elapsed.min(limit));
+        #[cfg(feature = "alloc-count")]
+        let allocs = AllocCount::current().sub(self.allocs_mark);
+        self.record_slice(key, slice, now);
+        #[cfg(feature = "alloc-count")]
+        if let Some(state) = self.times.get_mut(key) {
+            state.allocs = state.allocs.add(allocs);
+        }
+        if elapsed > slice {
+            self.record_slice("<idle>", elapsed.saturating_sub(slice), now);
+        }
+        Some(slice)
+    }
+
+    /// Count a time slice of `dur` ending at `now` for the state named "key".
+    fn record_slice(&mut self, key: &'static str, dur: Duration, now: Instant) {
+        let state = self.add_time(key, dur);
+        state.count += 1;
+        state.sum_sq = state
+            .sum_sq
+            .saturating_add(dur.as_nanos().saturating_pow(2));
+        if state.max_end.is_none() || dur > state.max {
+            state.max = dur;
+            state.max_end = Some(now);
+        }
+    }
+
+    /// Add `dur` to the total time of the state named "key",
+    /// clamping it at `Duration::MAX` instead of overflowing.
+    fn add_time(&mut self, key: &'static str, dur: Duration) -> &mut State {
+        let seq = self.times.len();
+        let state = self.times.entry(key).or_insert_with(|| State::new(seq));
+        if let Some(total) = state.duration.checked_add(dur) {
+            state.duration = total;
+        } else {
+            state.duration = Duration::MAX;
+            self.saturated = true;
+        }
+        state
+    }
+
+    fn state(&mut self, key: &'static str) -> &mut State {
+        let seq = self.times.len();
+        self.times.entry(key).or_insert_with(|| State::new(seq))
+    }
+
+    /// Stop counting time.
+    pub fn stop(&mut self) {
+        self.stop_at(Instant::now());
+    }
+
+    /// Stop counting time at the instant `at`.
+    ///
+    /// Together with [`start_at`](Self::start_at), this allows using the reporter
+    /// as an aggregator over recorded timelines, e.g. for deterministic tests.
+    ///
+    /// Instants are expected in monotonic order: if `at` is earlier than the
+    /// start of the current state, its time slice saturates to zero instead of panicking.
+    ///
+    /// Returns the time slice counted, capped by
+    /// [`auto_stop_after`](TimeReporterBuilder::auto_stop_after), or `None`
+    /// if no state was being counted.
+    pub fn stop_at(&mut self, at: Instant) -> Option<Duration> {
+        self.save_current(at)
+    }
+
+    /// Return the total time gathered for the state named "key".
+    #[must_use]
+    pub fn duration_for(&self, key: &str) -> Option<Duration> {
+        self.times.get(key).map(|s| s.duration)
+    }
+
+    /// Return the total time gathered for the state named "key", including
+    /// the time slice being counted if it is the current state.
+    ///
+    /// Unlike [`duration_for`](Self::duration_for), which only covers finished
+    /// time slices, this is how long the state has been active so far.
+    #[must_use]
+    pub fn elapsed_for(&self, key: &str) -> Option<Duration> {
+        let running = self
+            .cur_state_time
+            .filter(|(cur, _)| *cur == key)
+            .map(|(_, at)| Instant::now().saturating_duration_since(at));
+        match (self.duration_for(key), running) {
+            (Some(dur), Some(running)) => Some(dur.saturating_add(running)),
+            (dur, running) => dur.or(running),
+        }
+    }
+
+    /// Return the number of times the state named "key" was counted,
+    /// i.e. the number of time slices its total time is made of.
+    #[must_use]
+    pub fn count_for(&self, key: &str) -> Option<u64> {
+        self.times.get(key).map(|s| s.count)
+    }
+
+    /// Return the average time of a time slice of the state named "key".
+    ///
+    /// Returns `None` if the state was never counted.
+    #[must_use]
+    pub fn average_for(&self, key: &str) -> Option<Duration> {
+        let state = self.times.get(key).filter(|s| s.count > 0)?;
+        let nanos = state.duration.as_nanos() / u128::from(state.count);
+        Some(Duration::from_nanos(
+            u64::try_from(nanos).unwrap_or(u64::MAX),
+        ))
+    }
+
+    /// Return the standard deviation of the time slices of the state named "key".
+    ///
+    /// It is computed from running sums, without keeping each time slice, and helps
+    /// telling consistently slow states from bursty ones.
+    ///
+    /// Returns `None` if the state was never counted, or if time was added to it
+    /// without its time slices, by [`merge`](Self::merge),
+    /// [`merge_from_map`](Self::merge_from_map) or [`merge_max`](Self::merge_max).
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn stddev_for(&self, key: &str) -> Option<Duration> {
+        let state = self.times.get(key).filter(|s| s.count > 0 && !s.unsliced)?;
+        let count = state.count as f64;
+        let mean = state.duration.as_nanos() as f64 / count;
+        let variance = state.sum_sq as f64 / count - mean * mean;
+        Some(Duration::from_secs_f64(variance.max(0.0).sqrt() / 1e9))
+    }
+
+    /// Return the number of allocations made by the state named "key",
+    /// as counted by [`CountingAlloc`].
+    ///
+    /// Only allocations made on the thread that started the state are counted.
+    #[cfg(feature = "alloc-count")]
+    #[must_use]
+    pub fn allocs_for(&self, key: &str) -> Option<u64> {
+        self.times.get(key).map(|s| s.allocs.allocs)
+    }
+
+    /// Return the number of bytes allocated by the state named "key",
+    /// as counted by [`CountingAlloc`].
+    ///
+    /// Only allocations made on the thread that started the state are counted.
+    #[cfg(feature = "alloc-count")]
+    #[must_use]
+    pub fn bytes_for(&self, key: &str) -> Option<u64> {
+        self.times.get(key).map(|s| s.allocs.bytes)
+    }
+
+    /// Return the longest time slice of the state named "key".
+    #[must_use]
+    pub fn max_for(&self, key: &str) -> Option<Duration> {
+        self.times.get(key).filter(|s| s.count > 0).map(|s| s.max)
+    }
+
+    /// Return when the longest time slice of the state named "key" ended.
+    ///
+    /// This helps relate the slowest occurrence of a state to other logs.
+    /// It is computed from [`created_at`](Self::created_at) and the monotonic clock,
+    /// so later changes of the system clock are not reflected.
+    #[must_use]
+    pub fn max_at_for(&self, key: &str) -> Option<SystemTime> {
+        let end = self.times.get(key)?.max_end?;
+        let after = end.saturating_duration_since(self.created);
+        let before = self.created.saturating_duration_since(end);
+        self.created_at.checked_add(after)?.checked_sub(before)
+    }
+
+    /// Return whether time was gathered for a state named "key".
+    #[must_use]
+    pub fn contains(&self, key: &str) -> bool {
+        self.times.contains_key(key)
+    }
+
+    /// Return the number of states time was gathered for.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.times.len()
+    }
+
+    /// Return whether no time was gathered for any state.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.times.is_empty()
+    }
+
+    /// Return the sum of the total times gathered for all states.
+    #[must_use]
+    pub fn total(&self) -> Duration {
+        self.times
+            .values()
+            .fold(Duration::ZERO, |total, s| total.saturating_add(s.duration))
+    }
+
+    /// Return the total time of the state named "key" as a percentage of the
+    /// [total time](Self::total) of all states.
+    ///
+    /// Returns `None` if the state wasn't counted or the total time is zero.
+    #[must_use]
+    pub fn percent_for(&self, key: &str) -> Option<f64> {
+        let total = self.total();
+        let dur = self.duration_for(key).filter(|_| !total.is_zero())?;
+        Some(dur.div_duration_f64(total) * 100.0)
+    }
+
+    /// Return the state with the longest total time, and its total time.
+    #[must_use]
+    pub fn hottest(&self) -> Option<(&'static str, Duration)> {
+        let (&key, state) = self.times.iter().max_by_key(|(_, s)| s.duration)?;
+        Some((key, state.duration))
+    }
+
+    /// Return the ratio of the longest total time to the second longest.
+    ///
+    /// A high ratio means a single state dominates. It is infinite if the second
+    /// longest total time is zero, and `None` if fewer than two states were counted.
+    #[must_use]
+    pub fn dominance(&self) -> Option<f64> {
+        let (mut first, mut second) = (None, None);
+        for state in self.times.values() {
+            if first.is_none_or(|first| state.duration > first) {
+                second = first;
+                first = Some(state.duration);
+            } else if second.is_none_or(|second| state.duration > second) {
+                second = Some(state.duration);
+            }
+        }
+        let (first, second) = (first?, second?);
+        if second.is_zero() {
+            return Some(f64::INFINITY);
+        }
+        Some(first.div_duration_f64(second))
+    }
+
+    /// Return the state with the shortest total time, and its total time.
+    #[must_use]
+    pub fn coldest(&self) -> Option<(&'static str, Duration)> {
+        let (&key, state) = self.times.iter().min_by_key(|(_, s)| s.duration)?;
+        Some((key, state.duration))
+    }
+
+    /// Return the instant at which the time slice of the current state started,
+    /// or `None` if no state is being counted.
+    #[must_use]
+    pub fn current_start(&self) -> Option<Instant> {
+        self.cur_state_time.map(|(_, at)| at)
+    }
+
+    /// Stop counting time and forget all total times gathered so far.
+    ///
+    /// The reporter's configuration and allocated capacity are kept,
+    /// so it can be reused for a new set of activities. The numbering of
+    /// [`next_phase`](Self::next_phase) starts again from 1, and the
+    /// [`max_depth`](Self::max_depth) is reset to the current nesting.
+    pub fn clear(&mut self) {
+        self.cur_state_time = None;
+        self.times.clear();
+        self.saturated = false;
+        self.phase = 0;
+        self.max_depth = self.depth;
+    }
+
+    /// Forget the time gathered for the state named "key", but keep it in reports.
+    ///
+    /// If "key" is the current state, its time slice starts again now,
+    /// so a new measurement window can be started for a single state.
+    pub fn reset_key(&mut self, key: &'static str) {
+        if let Some(state) = self.times.get_mut(key) {
+            *state = State::new(state.seq);
+        }
+        if let Some((cur, at)) = &mut self.cur_state_time {
+            if *cur == key {
+                *at = Instant::now();
+                #[cfg(feature = "alloc-count")]
+                {
+                    self.allocs_mark = AllocCount::current();
+                }
+            }
+        }
+    }
+
+    /// Forget the time gathered for every state except those in `keep`,
+    /// as with [`reset_key`](Self::reset_key).
+    ///
+    /// This allows keeping some cumulative states across measurement windows.
+    pub fn reset_all_but(&mut self, keep: &[&'static str]) {
+        let keys: Vec<_> = self
+            .times
+            .keys()
+            .copied()
+            .chain(self.cur_state_time.map(|(key, _)| key))
+            .filter(|key| !keep.contains(key))
+            .collect();
+        for key in keys {
+            self.reset_key(key);
+        }
+    }
+
+    /// Return whether a total time reached `Duration::MAX` and was clamped there,
+    /// making it smaller than the actual time spent.
+    #[must_use]
+    pub const fn saturated(&self) -> bool {
+        self.saturated
+    }
+
+    /// Shrink the capacity used to store total times as much as possible.
+    pub fn shrink_to_fit(&mut self) {
+        self.times.shrink_to_fit();
+    }
+
+    /// Return the number of states that can be stored without reallocating.
+    #[must_use]
+    pub fn capacity(&self) -> usize {
+        self.times.capacity()
+    }
+
+    /// Add the total times gathered by `other` to this `TimeReporter`.
+    ///
+    /// This is useful to aggregate the times of several reporters,
+    /// e.g. one per thread, into a single report.
+    ///
+    /// Only the total times are added, the counts are left as they are.
+    /// Use [`merge_weighted`](Self::merge_weighted) to keep averages and
+    /// standard deviations correct.
+    pub fn merge(&mut self, other: &Self) {
+        for (&key, other) in &other.times {
+            self.add_time(key, other.duration).unsliced = true;
+        }
+    }
+
+    /// Add the total times in `map`, e.g. measured by other means, to this `TimeReporter`.
+    ///
+    /// This is the inverse of [`to_map`](Self::to_map). As with [`merge`](Self::merge),
+    /// the counts are left as they are.
+    pub fn merge_from_map(&mut self, map: &std::collections::HashMap<&'static str, Duration>) {
+        for (&key, &dur) in map {
+            self.add_time(key, dur).unsliced = true;
+        }
+    }
+
+    /// Add the total times and the counts gathered by `other` to this `TimeReporter`.
+    ///
+    /// Unlike [`merge`](Self::merge), this keeps [`average_for`](Self::average_for)
+    /// correct when the reporters counted states a different number of times.
+    /// The longest time slices are also merged.
+    pub fn merge_weighted(&mut self, other: &Self) {
+        for (&key, other) in &other.times {
+            self.merge_state(key, other);
+        }
+    }
+
+    /// Merge the times gathered by `other` like [`merge_weighted`](Self::merge_weighted),
+    /// but with its state names prefixed with its name and `::`.
+    ///
+    /// This keeps apart the states of subsystems that reuse the same names.
+    /// Each distinct prefixed name is allocated once and kept for the rest of the program.
+    pub fn merge_named(&mut self, other: &Self) {
+        for (&key, state) in &other.times {
+            let key = intern(format!("{}::{key}", other.config.name));
+            self.merge_state(key, state);
+        }
+    }
+
+    fn merge_state(&mut self, key: &'static str, other: &State) {
+        let state = self.add_time(key, other.duration);
+        state.count += other.count;
+        state.sum_sq = state.sum_sq.saturating_add(other.sum_sq);
+        state.unsliced |= other.unsliced;
+        #[cfg(feature = "alloc-count")]
+        {
+            state.allocs = state.allocs.add(other.allocs);
+        }
+        if state.max_end.is_none() || other.max > state.max {
+            state.max = other.max;
+            state.max_end = other.max_end;
+        }
+    }
+
+    /// Keep the maximum of the total times gathered by this `TimeReporter` and `other`.
+    ///
+    /// Unlike [`merge`](Self::merge), which reports the sum over reporters,
+    /// this reports the worst case, e.g. the slowest thread for each state.
+    /// The counts are left as they are.
+    pub fn merge_max(&mut self, other: &Self) {
+        for (&key, other) in &other.times {
+            let state = self.state(key);
+            state.duration = state.duration.max(other.duration);
+            state.unsliced = true;
+        }
+    }
+
+    /// Finish counting time and report results.
+    #[allow(clippy::unused_self)]
+    pub fn finish(self) {}
+
+    /// Wrap `fut` into a future owning this `TimeReporter`, which reports results
+    /// when the future completes or is dropped, e.g. to report per request handler.
+    pub async fn instrument_future<F: std::future::Future>(self, fut: F) -> F::Output {
+        let _reporter = self;
+        fut.await
+    }
+
+    /// Return a guard that reports results when it goes out of scope.
+    ///
+    /// This makes the report on drop explicit, and warns if the guard is unused.
+    pub const fn guard(self) -> ReporterGuard {
+        ReporterGuard(self)
+    }
+
+    /// Finish counting time and return the formatted report instead of emitting it.
+    #[must_use]
+    pub fn finish_to_string(mut self) -> String {
+        self.suppress_report = true;
+        self.finish_running();
+        self.to_string()
+    }
+
+    /// Count the time of the state still running when finishing, so it isn't lost,
+    /// unless disabled with [`commit_running_on_drop`](TimeReporterBuilder::commit_running_on_drop).
+    fn finish_running(&mut self) {
+        if let Some((state, _)) = self.cur_state_time {
+            if self.config.warn_unterminated {
+                ::tracing::warn!(
+                    target: "tracing-perf",
+                    state,
+                    "state was still running when the time reporter finished"
+                );
+            }
+            if self.config.commit_running_on_drop {
+                self.stop();
+            } else {
+                self.cur_state_time = None;
+            }
+        }
+    }
+
+    /// Return a builder with the configuration of this `TimeReporter`.
+    ///
+    /// This is handy to create a similar reporter with a few options changed.
+    #[must_use]
+    pub fn to_builder(&self) -> TimeReporterBuilder {
+        self.config.clone()
+    }
+
+    /// Turn this `TimeReporter` back into a builder with its configuration,
+    /// discarding the total times gathered without reporting them.
+    #[must_use]
+    pub fn into_builder(mut self) -> TimeReporterBuilder {
+        self.suppress_report = true;
+        self.to_builder()
+    }
+
+    /// Return whether the current subscriber is interested in the reports of this `TimeReporter`,
+    /// given its level and the `tracing-perf` target.
+    ///
+    /// This can be used to skip expensive instrumentation when nothing would be recorded.
+    #[must_use]
+    pub fn is_enabled(&self) -> bool {
+        _enabled!(target: "tracing-perf", self.config.level)
+    }
+
+    /// Report the total times gathered so far, without finishing.
+    pub fn report(&self) {
+        let mut buf = String::new();
+        self.report_into(&mut buf);
+    }
+
+    /// Report the total times gathered so far, formatting them into `buf`.
+    ///
+    /// `buf` is cleared before use, so the same buffer can be reused
+    /// across reports to avoid allocating a new `String` each time.
+    pub fn report_into(&self, buf: &mut String) {
+        use std::fmt::Write;
+
+        buf.clear();
+        let _ = write!(buf, "{self}");
+        self.emit(buf);
+    }
+
+    /// Send the total times gathered so far to each of `sinks`.
+    ///
+    /// The report is formatted once and shared by all sinks.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first error encountered writing to a [`ReportSink::Writer`].
+    /// The report is still sent to the remaining sinks.
+    pub fn report_to(&self, sinks: &mut [ReportSink]) -> io::Result<()> {
+        let report = self.to_string();
+        let mut result = Ok(());
+        for sink in sinks {
+            match sink {
+                ReportSink::Tracing => self.emit(&report),
+                ReportSink::Writer(writer) => {
+                    let written = writeln!(writer, "{report}");
+                    result = result.and(written);
+                }
+                ReportSink::Callback(callback) => callback(&report),
+            }
+        }
+        result
+    }
+
+    /// Register a function to call whenever this `TimeReporter` reports,
+    /// on `drop`, `finish` or `report`, before the `tracing` events are emitted.
+    ///
+    /// It replaces any previously registered function. To call it instead of
+    /// emitting the summary message, disable [`summary`](TimeReporterBuilder::summary).
+    pub fn on_report(&mut self, f: impl Fn(&Self) + Send + Sync + 'static) {
+        self.on_report = Some(ReportHook(Arc::new(f)));
+    }
+
+    /// Return the creation time formatted as set with
+    /// [`started_at_format`](TimeReporterBuilder::started_at_format), if shown.
+    #[cfg(feature = "chrono")]
+    fn formatted_started_at(&self) -> Option<String> {
+        use std::fmt::Write;
+
+        let format = self.config.started_at_format.as_deref();
+        let format = format.filter(|_| self.config.show_started_at)?;
+        let created_at = chrono::DateTime::<chrono::Utc>::from(self.created_at);
+        let mut started_at = String::new();
+        if write!(started_at, "{}", created_at.format(format)).is_err() {
+            return Some(created_at.to_rfc3339());
+        }
+        Some(started_at)
+    }
+
+    #[cfg(not(feature = "chrono"))]
+    #[allow(clippy::unused_self)]
+    const fn formatted_started_at(&self) -> Option<String> {
+        None
+    }
+
+    /// Return the level to emit the report at, see [`TimeReporterBuilder::escalate_if`].
+    fn report_level(&self) -> Level {
+        match self.config.escalate {
+            Some((threshold, level)) if self.total() > threshold => level.min(self.config.level),
+            _ => self.config.level,
+        }
+    }
+
+    fn emit<T: fmt::Display>(&self, report: T) {
+        let level = self.report_level();
+        #[cfg(debug_assertions)]
+        self.check_width();
+        if self.config.dedupe_reports {
+            let report = report.to_string();
+            let mut last = self
+                .last_report
+                .0
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            if last.as_deref() == Some(&*report) {
+                return;
+            }
+            *last = Some(report);
+        }
+        if let Some(hook) = &self.on_report {
+            (hook.0)(self);
+        }
+        #[cfg(feature = "log")]
+        if self.config.log_backend {
+            if self.config.summary {
+                ::log::log!(target: "tracing-perf", log_level(level), "{report}");
+            }
+            return;
+        }
+        let panicked = std::thread::panicking().then_some(true);
+        let name = self.config.name_as_field.then_some(&*self.config.name);
+        let span = if self.config.wrap_in_span {
+            _span!(level, "time-report", name, panicked)
+        } else {
+            Span::none()
+        };
+        let _span = span.entered();
+        let (name, panicked) = if self.config.wrap_in_span {
+            (None, None)
+        } else {
+            (name, panicked)
+        };
+        if self.config.summary {
+            let states = self.config.show_state_count.then_some(self.times.len());
+            let thread = self.config.show_thread.then(|| {
+                let thread = std::thread::current();
+                thread
+                    .name()
+                    .map_or_else(|| format!("{:?}", thread.id()), str::to_owned)
+            });
+            let thread = thread.as_deref();
+            if let Some(started_at) = self.formatted_started_at() {
+                let started_at = started_at.as_str();
+                _event!(target: "tracing-perf", level, name, panicked, started_at, states, thread, "{report}");
+            } else {
+                let started_at = self.config.show_started_at.then(|| {
+                    let since_epoch = self.created_at.duration_since(SystemTime::UNIX_EPOCH);
+                    since_epoch.unwrap_or_default().as_secs_f64()
+                });
+                _event!(target: "tracing-perf", level, name, panicked, started_at, states, thread, "{report}");
+            }
+        }
+        if self.config.per_state_events {
+            for (state, dur) in self.stats() {
+                let tags = self.tags.get(state).map(|tags| {
+                    let tags: Vec<_> = tags.iter().map(|(k, v)| format!("{k}={v}")).collect();
+                    tags.join(",")
+                });
+                #[cfg(feature = "alloc-count")]
+                let (allocs, bytes) = (self.allocs_for(state), self.bytes_for(state));
+                #[cfg(not(feature = "alloc-count"))]
+                let (allocs, bytes): (Option<u64>, Option<u64>) = (None, None);
+                _event!(target: "tracing-perf", level, name, panicked, state, duration_secs = dur.as_secs_f64(), tags, allocs, bytes);
+            }
+        }
+    }
+
+    /// Warn once, in debug builds, if the configured width is too small to have any effect.
+    #[cfg(debug_assertions)]
+    fn check_width(&self) {
+        static WARNED: std::sync::Once = std::sync::Once::new();
+
+        let width = self.config.width;
+        let precision = self.config.precision;
+        if width > 0 && precision + 2 > width {
+            WARNED.call_once(|| {
+                ::tracing::warn!(
+                    target: "tracing-perf",
+                    width,
+                    precision,
+                    "width should be at least precision + 2 for the total times to be padded"
+                );
+            });
+        }
+    }
+
+    /// Run `f` with a new `TimeReporter` named "name" and return its formatted report,
+    /// instead of emitting it, so reports can be checked without a `tracing` subscriber.
+    #[cfg(feature = "testing")]
+    pub fn capture<S, F>(name: S, f: F) -> String
+    where
+        S: Into<Cow<'static, str>>,
+        F: FnOnce(&mut Self),
+    {
+        let mut reporter = Self::new(name);
+        f(&mut reporter);
+        reporter.finish_to_string()
+    }
+
+    /// Assert that the [coverage](Self::coverage) of this `TimeReporter` is at least `min`,
+    /// e.g. `0.95`, to check that no code path misses instrumentation.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the coverage is below `min`, with the fraction of time no state was counted.
+    #[cfg(feature = "testing")]
+    pub fn assert_coverage(&self, min: f64) {
+        let coverage = self.coverage();
+        assert!(
+            coverage >= min,
+            "coverage of {} is {coverage:.3}, below {min:.3}: {:.3} of its lifetime is untracked",
+            self.config.name,
+            1.0 - coverage
+        );
+    }
+
+    /// Compare total times against a `baseline` and describe the regressions.
+    ///
+    /// The baseline is typically a [`snapshot`](Self::snapshot) of an earlier run.
+    ///
+    /// A state regressed when its total time exceeds the baseline's by more than
+    /// `tolerance`, given as a fraction (e.g. `0.1` for 10%). States missing from
+    /// the baseline are ignored.
+    ///
+    /// Returns `None` if no state regressed, otherwise one line per regressed state
+    /// listing the old time, the new time and the percent change.
+    #[cfg(feature = "testing")]
+    #[must_use]
+    pub fn regression_report(&self, baseline: &TimeReport, tolerance: f64) -> Option<String> {
+        use std::fmt::Write;
+
+        let precision = self.config.precision;
+        let mut report = String::new();
+        for (state, new) in self.stats() {
+            let Some(old) = baseline.duration_for(state) else {
+                continue;
+            };
+            let (old, new) = (old.as_secs_f64(), new.as_secs_f64());
+            if new > old * (1.0 + tolerance) {
+                let change = if old > 0.0 {
+                    (new - old) / old * 100.0
+                } else {
+                    f64::INFINITY
+                };
+                let _ = writeln!(
+                    report,
+                    "{state}: {old:.precision$} -> {new:.precision$} (+{change:.1}%)"
+                );
+            }
+        }
+
+        if report.is_empty() {
+            None
+        } else {
+            Some(format!(
+                "name: {}, regressions:\n{report}",
+                self.config.name
+            ))
+        }
+    }
+
+    /// Return the total times gathered so far, sorted by the printing order.
+    ///
+    /// The currently running state is only included if enabled with
+    /// [`include_running`](TimeReporterBuilder::include_running). States are filtered
+    /// with [`include_only`](TimeReporterBuilder::include_only) and
+    /// [`exclude`](TimeReporterBuilder::exclude), then small states are folded as set
+    /// with [`other_threshold`](TimeReporterBuilder::other_threshold).
+    ///
+    /// The report and all exports list the states in this order.
+    #[must_use]
+    pub fn stats(&self) -> Vec<(&'static str, Duration)> {
+        let stats = self.sorted_states();
+        stats.into_iter().map(|(k, s)| (k, s.duration)).collect()
+    }
+
+    /// Return the states of [`stats`](Self::stats) with their total time and its
+    /// percentage of the total time of these states, e.g. for charts and tables.
+    #[must_use]
+    pub fn breakdown(&self) -> Vec<(&'static str, Duration, f64)> {
+        let stats = self.stats();
+        let total = stats
+            .iter()
+            .fold(Duration::ZERO, |total, s| total.saturating_add(s.1));
+        stats
+            .into_iter()
+            .map(|(key, dur)| {
+                let percent = if total.is_zero() {
+                    0.0
+                } else {
+                    dur.div_duration_f64(total) * 100.0
+                };
+                (key, dur, percent)
+            })
+            .collect()
+    }
+
+    /// Return the total times gathered so far by state name, for lookups.
+    ///
+    /// The states are those of [`stats`](Self::stats).
+    #[must_use]
+    pub fn to_map(&self) -> std::collections::HashMap<&'static str, Duration> {
+        self.stats().into_iter().collect()
+    }
+
+    /// Return the total times gathered so far by state name, in seconds,
+    /// as printed in the report.
+    #[must_use]
+    pub fn to_secs_map(&self) -> std::collections::HashMap<&'static str, f64> {
+        self.stats()
+            .into_iter()
+            .map(|(key, dur)| (key, dur.as_secs_f64()))
+            .collect()
+    }
+
+    /// Return a snapshot of the total times gathered so far, sorted by the printing order.
+    ///
+    /// The entries are those of [`stats`](Self::stats), so a serialized snapshot
+    /// lists the states as the report does.
+    #[must_use]
+    pub fn snapshot(&self) -> TimeReport {
+        TimeReport {
+            name: self.config.name.to_string(),
+            entries: self
+                .sorted_states()
+                .into_iter()
+                .map(|(k, s)| (k.to_owned(), s.duration, s.count))
+                .collect(),
+        }
+    }
+
+    /// Return the total times gathered so far in the Prometheus text exposition format.
+    ///
+    /// Each state has a `tracing_perf_state_seconds` sample with its total time
+    /// and a `tracing_perf_state_count` sample with its count, both labelled
+    /// with the reporter name and the state name.
+    ///
+    /// The states are listed as in [`stats`](Self::stats).
+    #[cfg(feature = "prometheus")]
+    #[must_use]
+    pub fn to_prometheus(&self) -> String {
+        use std::fmt::Write;
+
+        fn escape(value: &str) -> String {
+            value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n")
+        }
+
+        let reporter = escape(&self.config.name);
+        let states: Vec<_> = self
+            .sorted_states()
+            .into_iter()
+            .map(|(key, state)| (escape(key), state))
+            .collect();
+        let mut out = String::new();
+        out.push_str("# HELP tracing_perf_state_seconds Total time spent in each state.\n");
+        out.push_str("# TYPE tracing_perf_state_seconds counter\n");
+        for (key, state) in &states {
+            let secs = state.duration.as_secs_f64();
+            let _ = writeln!(
+                out,
+                "tracing_perf_state_seconds{{reporter=\"{reporter}\",state=\"{key}\"}} {secs}"
+            );
+        }
+        out.push_str("# HELP tracing_perf_state_count Number of times each state was counted.\n");
+        out.push_str("# TYPE tracing_perf_state_count counter\n");
+        for (key, state) in &states {
+            let count = state.count;
+            let _ = writeln!(
+                out,
+                "tracing_perf_state_count{{reporter=\"{reporter}\",state=\"{key}\"}} {count}"
+            );
+        }
+        out
+    }
+
+    fn sorted_states(&self) -> Vec<(&'static str, State)> {
+        let mut stats: Vec<_> = self.times.iter().map(|(&k, &v)| (k, v)).collect();
+        if let Some((key, start)) = self.cur_state_time.filter(|_| self.config.include_running) {
+            let elapsed = Instant::now().saturating_duration_since(start);
+            let running = self
+                .config
+                .auto_stop_after
+                .map_or(elapsed, |limit| elapsed.min(limit));
+            match stats.iter_mut().find(|s| s.0 == key) {
+                Some(stat) => stat.1.duration = stat.1.duration.saturating_add(running),
+                None => stats.push((
+                    key,
+                    State {
+                        duration: running,
+                        ..State::new(self.times.len())
+                    },
+                )),
+            }
+        }
+        if let Some(include_only) = &self.config.include_only {
+            stats.retain(|s| include_only.contains(&s.0));
+        }
+        stats.retain(|s| !self.config.exclude.contains(&s.0));
+        #[cfg(feature = "regex")]
+        if let Some(StateRegex(regex)) = &self.config.filter_regex {
+            stats.retain(|s| regex.is_match(s.0));
+        }
+        if let Some(threshold) = self.config.other_threshold {
+            let mut other: Option<State> = None;
+            stats.retain(|&(key, state)| {
+                if state.duration >= threshold && key != "<other>" {
+                    return true;
+                }
+                let other = other.get_or_insert_with(|| State::new(state.seq));
+                other.duration = other.duration.saturating_add(state.duration);
+                other.count += state.count;
+                other.sum_sq = other.sum_sq.saturating_add(state.sum_sq);
+                other.unsliced |= state.unsliced;
+                #[cfg(feature = "alloc-count")]
+                {
+                    other.allocs = other.allocs.add(state.allocs);
+                }
+                if other.max_end.is_none() || state.max > other.max {
+                    other.max = state.max;
+                    other.max_end = state.max_end;
+                }
+                other.seq = other.seq.min(state.seq);
+                false
+            });
+            stats.extend(other.map(|other| ("<other>", other)));
+        }
+        let (order, secondary) = (self.config.print_order, self.config.secondary_order);
+        if let Some(StatsOrder(compare)) = &self.config.sort_by {
+            stats.sort_by(|a, b| {
+                compare(
+                    &(a.0, a.1.duration, a.1.count),
+                    &(b.0, b.1.duration, b.1.count),
+                )
+            });
+        } else {
+            stats.sort_by(|a, b| {
+                let ordering = order.cmp_states(a, b);
+                secondary.map_or(ordering, |secondary| {
+                    ordering.then_with(|| secondary.cmp_states(a, b))
+                })
+            });
+        }
+        stats
+    }
+
+    fn fmt_stat(
+        &self,
+        f: &mut impl fmt::Write,
+        separator: &str,
+        state: &str,
+        dur: Duration,
+        max: Option<Duration>,
+    ) -> fmt::Result {
+        f.write_str(separator)?;
+        self.fmt_name(f, state)?;
+        f.write_str(": ")?;
+        self.fmt_value(f, dur, max, self.config.width)
+    }
+
+    /// Format `dur`, or its fraction of `max` if [normalized](TimeReporterBuilder::normalized).
+    fn fmt_value(
+        &self,
+        f: &mut impl fmt::Write,
+        dur: Duration,
+        max: Option<Duration>,
+        width: usize,
+    ) -> fmt::Result {
+        let Some(max) = max.filter(|_| self.config.normalized) else {
+            return self.fmt_duration(f, dur, width);
+        };
+        let precision = self.config.precision;
+        let ratio = if max.is_zero() {
+            0.0
+        } else {
+            dur.div_duration_f64(max)
+        };
+        let ratio = format!("{ratio:<width$.precision$}");
+        let sep = self.config.decimal_separator;
+        write!(
+            f,
+            "{}",
+            ratio.replacen('.', sep.encode_utf8(&mut [0; 4]), 1)
+        )
+    }
+
+    // The conversion to f64 is lossy beyond 2^53 nanoseconds, see `precision`
+    #[allow(clippy::cast_precision_loss)]
+    fn fmt_duration(&self, f: &mut impl fmt::Write, dur: Duration, width: usize) -> fmt::Result {
+        let precision = self.config.precision;
+        let sep = self.config.decimal_separator;
+        if let Some(TickRate(ticks_per_second)) = self.config.ticks_per_second {
+            let ticks = dur.as_secs_f64() * ticks_per_second;
+            let ticks = format!("{ticks:.precision$}");
+            let ticks = ticks.replacen('.', sep.encode_utf8(&mut [0; 4]), 1);
+            let text = ticks + &self.config.tick_unit;
+            return write!(f, "{text:<width$}");
+        }
+        if self.config.iso8601 {
+            let dur = self.config.rounding.round_exact(dur, precision);
+            let nanos = format!("{:09}", dur.subsec_nanos());
+            let frac = nanos[..precision.min(9)].trim_end_matches('0');
+            let text = if frac.is_empty() {
+                format!("PT{}S", dur.as_secs())
+            } else {
+                format!("PT{}{sep}{frac}S", dur.as_secs())
+            };
+            return write!(f, "{text:<width$}");
+        }
+        if self.config.exact_durations {
+            let dur = self.config.rounding.round_exact(dur, precision);
+            let mut text = dur.as_secs().to_string();
+            if precision > 0 {
+                let nanos = format!("{:09}", dur.subsec_nanos());
+                text.push(sep);
+                text.push_str(&nanos[..precision.min(9)]);
+                text.extend(std::iter::repeat_n('0', precision.saturating_sub(9)));
+            }
+            return write!(f, "{text:<width$}");
+        }
+        let dur = self.config.rounding.round(dur, precision);
+        let dur = dur.as_secs() as f64 + f64::from(dur.subsec_nanos()) / 1_000_000_000_f64;
+        if sep == '.' {
+            write!(f, "{dur:<width$.precision$}")
+        } else {
+            let dur = format!("{dur:<width$.precision$}");
+            write!(f, "{}", dur.replacen('.', sep.encode_utf8(&mut [0; 4]), 1))
+        }
+    }
+
+    /// Format the report as `key{assign}value` tokens separated by `separator`,
+    /// for [`kv_format`](TimeReporterBuilder::kv_format) and
+    /// [`compact`](TimeReporterBuilder::compact).
+    fn fmt_tokens(&self, f: &mut impl fmt::Write, assign: char, separator: &str) -> fmt::Result {
+        let sanitize = |key: &str| -> String {
+            key.chars()
+                .map(|c| {
+                    if c.is_whitespace() || c == assign {
+                        '_'
+                    } else {
+                        c
+                    }
+                })
+                .collect()
+        };
+        let mut sep = if self.config.name_as_field {
+            ""
+        } else {
+            write!(f, "name{assign}{}", sanitize(&self.config.name))?;
+            separator
+        };
+        let stats = self.stats();
+        let max = stats.iter().map(|s| s.1).max();
+        for (state, dur) in stats {
+            write!(f, "{sep}{}{assign}", sanitize(state))?;
+            self.fmt_value(f, dur, max, 0)?;
+            sep = separator;
+        }
+        Ok(())
+    }
+
+    fn fmt_name(&self, f: &mut impl fmt::Write, name: &str) -> fmt::Result {
+        let name_width = self.config.name_width;
+        let width = str_width(name);
+        let pad = |f: &mut _, len| {
+            (0..len).try_for_each(|_| fmt::Write::write_char(f, self.config.leader))
+        };
+        if self.config.truncate_names && name_width > 0 && width > name_width {
+            // Keep as much of the name as fits before the ellipsis
+            let (mut end, mut prefix_width) = (0, 0);
+            for (i, c) in name.char_indices() {
+                if prefix_width + char_width(c) >= name_width {
+                    break;
+                }
+                prefix_width += char_width(c);
+                end = i + c.len_utf8();
+            }
+            write!(f, "{}…", &name[..end])?;
+            pad(f, name_width - 1 - prefix_width)
+        } else {
+            f.write_str(name)?;
+            pad(f, name_width.saturating_sub(width))
+        }
+    }
+}
+
+/// A guard counting time for a state of a `TimeReporter` until dropped,
+/// see [`TimeReporter::scope`].
+///
+/// It dereferences to the `TimeReporter`, so it can be used to start nested scopes.
+#[derive(Debug)]
+#[must_use = "the state is only counted until the guard is dropped"]
+pub struct Scope<'a> {
+    reporter: &'a mut TimeReporter,
+    prev: Option<&'static str>,
+}
+
+impl std::ops::Deref for Scope<'_> {
+    type Target = TimeReporter;
+
+    fn deref(&self) -> &TimeReporter {
+        self.reporter
+    }
+}
+
+impl std::ops::DerefMut for Scope<'_> {
+    fn deref_mut(&mut self) -> &mut TimeReporter {
+        self.reporter
+    }
+}
+
+impl Drop for Scope<'_> {
+    fn drop(&mut self) {
+        self.reporter.depth -= 1;
+        match self.prev {
+            Some(key) => self.reporter.start(key),
+            None => self.reporter.stop(),
         }
-    }};
+    }
 }
 
-macro_rules! _event {
-    (target: $target:expr, $lvl:expr, $($args:tt)*) => {{
-        match $lvl {
-            Level::ERROR => ::tracing::event!(target: $target, Level::ERROR, $($args)*),
-            Level::WARN  => ::tracing::event!(target: $target, Level::WARN,  $($args)*),
-            Level::INFO  => ::tracing::event!(target: $target, Level::INFO,  $($args)*),
-            Level::DEBUG => ::tracing::event!(target: $target, Level::DEBUG, $($args)*),
-            Level::TRACE => ::tracing::event!(target: $target, Level::TRACE, $($args)*),
+/// A `TimeReporter` whose total time is added to a state of its parent when dropped,
+/// see [`TimeReporter::child`].
+///
+/// It dereferences to the child `TimeReporter`, so it can have children of its own.
+#[derive(Debug)]
+#[must_use = "the child's time is added to its parent as soon as it is dropped"]
+pub struct ChildReporter<'a> {
+    parent: &'a mut TimeReporter,
+    key: &'static str,
+    prev: Option<&'static str>,
+    reporter: TimeReporter,
+    breakdown: bool,
+}
+
+impl ChildReporter<'_> {
+    /// Set whether the states of the child are also merged into the parent when it is
+    /// dropped, prefixed with the child's key and `::`, as with [`TimeReporter::merge_named`].
+    ///
+    /// The child's time is then counted twice in the parent's [`total`](TimeReporter::total).
+    pub const fn breakdown(&mut self, breakdown: bool) -> &mut Self {
+        self.breakdown = breakdown;
+        self
+    }
+}
+
+impl std::ops::Deref for ChildReporter<'_> {
+    type Target = TimeReporter;
+
+    fn deref(&self) -> &TimeReporter {
+        &self.reporter
+    }
+}
+
+impl std::ops::DerefMut for ChildReporter<'_> {
+    fn deref_mut(&mut self) -> &mut TimeReporter {
+        &mut self.reporter
+    }
+}
+
+impl Drop for ChildReporter<'_> {
+    fn drop(&mut self) {
+        self.reporter.finish_running();
+        let total = self.reporter.total();
+        let slice = State {
+            duration: total,
+            count: 1,
+            sum_sq: total.as_nanos().saturating_pow(2),
+            max: total,
+            max_end: Some(Instant::now()),
+            ..State::new(0)
+        };
+        self.parent.merge_state(self.key, &slice);
+        if self.breakdown {
+            self.parent.merge_named(&self.reporter);
         }
-    }};
+        if let Some(key) = self.prev {
+            self.parent.start(key);
+        }
+    }
+}
+
+/// A guard owning a `TimeReporter` that reports results when dropped,
+/// see [`TimeReporter::guard`].
+///
+/// It dereferences to the `TimeReporter`.
+#[derive(Debug)]
+#[must_use = "the report is emitted as soon as the guard is dropped"]
+pub struct ReporterGuard(TimeReporter);
+
+impl ReporterGuard {
+    /// Finish counting time and report results.
+    pub fn finish(self) {}
+}
+
+impl std::ops::Deref for ReporterGuard {
+    type Target = TimeReporter;
+
+    fn deref(&self) -> &TimeReporter {
+        &self.0
+    }
+}
+
+impl std::ops::DerefMut for ReporterGuard {
+    fn deref_mut(&mut self) -> &mut TimeReporter {
+        &mut self.0
+    }
+}
+
+/// A collector of the times gathered by several threads into a single report.
+///
+/// Each thread counts time with its own [`CollectorHandle`], without any locking,
+/// and its times are merged into the collector when the handle is dropped,
+/// as with [`TimeReporter::merge_weighted`]. The collector reports like a
+/// `TimeReporter` on `drop` or on call to `finish`.
+#[derive(Debug)]
+pub struct ScopedCollector {
+    builder: TimeReporterBuilder,
+    merged: Mutex<TimeReporter>,
+}
+
+impl ScopedCollector {
+    /// Create a new `ScopedCollector`.
+    pub fn new<S: Into<Cow<'static, str>>>(name: S) -> Self {
+        TimeReporterBuilder::new(name).into()
+    }
+
+    /// Return a new handle to count time for this collector,
+    /// e.g. for a worker thread.
+    pub fn handle(&self) -> CollectorHandle<'_> {
+        let mut reporter = self.builder.build();
+        reporter.suppress_report = true;
+        CollectorHandle {
+            collector: self,
+            reporter,
+        }
+    }
+
+    /// Return the `TimeReporter` the times of the dropped handles were merged into.
+    pub fn into_inner(self) -> TimeReporter {
+        self.merged
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Finish counting time and report results.
+    pub fn finish(self) {
+        self.into_inner().finish();
+    }
+}
+
+impl From<TimeReporterBuilder> for ScopedCollector {
+    fn from(builder: TimeReporterBuilder) -> Self {
+        let merged = Mutex::new(builder.build());
+        Self { builder, merged }
+    }
+}
+
+/// A `TimeReporter` for a single thread of a [`ScopedCollector`],
+/// whose times are merged into the collector when it is dropped.
+///
+/// It dereferences to the `TimeReporter`.
+#[derive(Debug)]
+pub struct CollectorHandle<'a> {
+    collector: &'a ScopedCollector,
+    reporter: TimeReporter,
+}
+
+impl std::ops::Deref for CollectorHandle<'_> {
+    type Target = TimeReporter;
+
+    fn deref(&self) -> &TimeReporter {
+        &self.reporter
+    }
+}
+
+impl std::ops::DerefMut for CollectorHandle<'_> {
+    fn deref_mut(&mut self) -> &mut TimeReporter {
+        &mut self.reporter
+    }
+}
+
+impl Drop for CollectorHandle<'_> {
+    fn drop(&mut self) {
+        self.reporter.finish_running();
+        let mut merged = self
+            .collector
+            .merged
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        merged.merge_weighted(&self.reporter);
+    }
+}
+
+/// A handle to a `TimeReporter` whose methods take `&self`, for single-threaded code.
+///
+/// It can be stored in a struct and used from several of its methods without
+/// passing `&mut TimeReporter` around. The reporter is kept in a `RefCell`, so the
+/// handle can't be shared between threads, and using it from within
+/// [`with`](Self::with) panics.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LocalTimeReporter(RefCell<TimeReporter>);
+
+impl LocalTimeReporter {
+    /// Create a new `LocalTimeReporter`.
+    pub fn new<S: Into<Cow<'static, str>>>(name: S) -> Self {
+        TimeReporter::new(name).into()
+    }
+
+    /// Start counting time for a state named "key", see [`TimeReporter::start`].
+    pub fn start(&self, key: &'static str) {
+        self.0.borrow_mut().start(key);
+    }
+
+    /// Start counting time and execute a function `f`, see [`TimeReporter::start_with`].
+    ///
+    /// Unlike [`with`](Self::with), the reporter may be used from within `f`.
+    pub fn start_with<F, R>(&self, key: &'static str, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        self.start(key);
+
+        f()
+    }
+
+    /// Stop counting time.
+    pub fn stop(&self) {
+        self.0.borrow_mut().stop();
+    }
+
+    /// Report the total times gathered so far, without finishing.
+    pub fn report(&self) {
+        self.0.borrow().report();
+    }
+
+    /// Execute a function `f` with mutable access to the reporter.
+    pub fn with<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut TimeReporter) -> R,
+    {
+        f(&mut self.0.borrow_mut())
+    }
+
+    /// Return the reporter this handle wraps.
+    #[must_use]
+    pub fn into_inner(self) -> TimeReporter {
+        self.0.into_inner()
+    }
+
+    /// Finish counting time and report results.
+    pub fn finish(self) {
+        self.into_inner().finish();
+    }
+}
+
+impl From<TimeReporter> for LocalTimeReporter {
+    fn from(reporter: TimeReporter) -> Self {
+        Self(RefCell::new(reporter))
+    }
+}
+
+/// Return a `'static` copy of `name`, leaking it only the first time it's seen.
+fn intern(name: String) -> &'static str {
+    static NAMES: Mutex<std::collections::BTreeSet<&'static str>> =
+        Mutex::new(std::collections::BTreeSet::new());
+
+    let mut names = NAMES.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(name) = names.get(name.as_str()) {
+        return name;
+    }
+    let name = Box::leak(name.into_boxed_str());
+    names.insert(name);
+    name
+}
+
+/// Compare strings ignoring case, falling back to a case-sensitive comparison for ties.
+fn cmp_ci<'a>(a: &'a str, b: &'a str) -> std::cmp::Ordering {
+    let lowercase = |s: &'a str| s.chars().flat_map(char::to_lowercase);
+    lowercase(a).cmp(lowercase(b)).then_with(|| a.cmp(b))
+}
+
+/// Compare strings in natural order, see [`PrintOrder::KeyNatural`].
+fn cmp_natural(a: &str, b: &str) -> std::cmp::Ordering {
+    use std::cmp::Ordering;
+
+    fn split_digits(s: &str) -> (&str, &str) {
+        s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()))
+    }
+
+    let (mut a_rest, mut b_rest) = (a, b);
+    loop {
+        match (a_rest.chars().next(), b_rest.chars().next()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (x_digits, x_rest) = split_digits(a_rest);
+                let (y_digits, y_rest) = split_digits(b_rest);
+                let x_num = x_digits.trim_start_matches('0');
+                let y_num = y_digits.trim_start_matches('0');
+                let ordering = x_num.len().cmp(&y_num.len()).then_with(|| x_num.cmp(y_num));
+                if ordering.is_ne() {
+                    return ordering;
+                }
+                (a_rest, b_rest) = (x_rest, y_rest);
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(&y);
+                }
+                (a_rest, b_rest) = (&a_rest[x.len_utf8()..], &b_rest[y.len_utf8()..]);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "log")]
+const fn log_level(level: Level) -> log::Level {
+    match level {
+        Level::ERROR => log::Level::Error,
+        Level::WARN => log::Level::Warn,
+        Level::INFO => log::Level::Info,
+        Level::DEBUG => log::Level::Debug,
+        Level::TRACE => log::Level::Trace,
+    }
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "unicode-width")] {
+        use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+        fn str_width(s: &str) -> usize {
+            s.width()
+        }
+
+        fn char_width(c: char) -> usize {
+            c.width().unwrap_or(0)
+        }
+    } else {
+        fn str_width(s: &str) -> usize {
+            s.chars().count()
+        }
+
+        const fn char_width(_: char) -> usize {
+            1
+        }
+    }
+}
+
+/// A snapshot of the total times gathered by a `TimeReporter`,
+/// independent of the live reporter.
+///
+/// It is created with [`TimeReporter::snapshot`], and can be kept,
+/// compared or exported without affecting the reporter.
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TimeReport {
+    /// Name of the reporter.
+    pub name: String,
+    /// State name, total time and count of each state, in printing order.
+    pub entries: Vec<(String, Duration, u64)>,
+}
+
+impl TimeReport {
+    /// Return the total time of the state named "key".
+    #[must_use]
+    pub fn duration_for(&self, key: &str) -> Option<Duration> {
+        let entry = self.entries.iter().find(|e| e.0 == key)?;
+        Some(entry.1)
+    }
+
+    /// Add the total times and counts of `other` to this `TimeReport`,
+    /// like [`TimeReporter::merge_weighted`] does for live reporters.
+    ///
+    /// States missing from this report are appended in the order of `other`.
+    pub fn merge(&mut self, other: &Self) {
+        for (key, dur, count) in &other.entries {
+            match self.entries.iter_mut().find(|e| e.0 == *key) {
+                Some(entry) => {
+                    entry.1 = entry.1.saturating_add(*dur);
+                    entry.2 = entry.2.saturating_add(*count);
+                }
+                None => self.entries.push((key.clone(), *dur, *count)),
+            }
+        }
+    }
+}
+
+/// Merges each report, see [`TimeReport::merge`].
+impl Extend<Self> for TimeReport {
+    fn extend<I: IntoIterator<Item = Self>>(&mut self, iter: I) {
+        for report in iter {
+            self.merge(&report);
+        }
+    }
+}
+
+/// Formats like a `TimeReporter` with the default options.
+/// The precision can be overridden, e.g. `{:.3}`.
+impl fmt::Display for TimeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let precision = f.precision().unwrap_or(9);
+        write!(f, "name: {}", self.name)?;
+        for (state, dur, _) in &self.entries {
+            write!(f, ", {state}: {:.precision$}", dur.as_secs_f64())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The last few [`TimeReport`]s of a reporter, kept in memory,
+/// e.g. to show live times in an interactive tool.
+///
+/// Once `capacity` reports are kept, recording a new one drops the oldest.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReportHistory {
+    reports: std::collections::VecDeque<TimeReport>,
+    capacity: usize,
+}
+
+impl ReportHistory {
+    /// Create a new `ReportHistory` keeping up to `capacity` reports.
+    #[must_use]
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            reports: std::collections::VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Keep `report`, dropping the oldest report if full.
+    pub fn push(&mut self, report: TimeReport) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.reports.len() == self.capacity {
+            self.reports.pop_front();
+        }
+        self.reports.push_back(report);
+    }
+
+    /// Keep a [`snapshot`](TimeReporter::snapshot) of `reporter`, dropping the oldest report if full.
+    pub fn record(&mut self, reporter: &TimeReporter) {
+        self.push(reporter.snapshot());
+    }
+
+    /// Return an iterator over the reports kept, from oldest to newest.
+    #[must_use]
+    pub fn recent(&self) -> std::collections::vec_deque::Iter<'_, TimeReport> {
+        self.reports.iter()
+    }
+}
+
+/// Measure the time spent executing `f` and report it.
+///
+/// This is a shorthand for creating a `TimeReporter` named `name` with
+/// the given `level`, timing `f` as the state named "key" and finishing it.
+pub fn time<R>(name: &str, key: &'static str, level: Level, f: impl FnOnce() -> R) -> R {
+    let mut reporter = TimeReporter::new_with_level(name.to_owned(), level);
+    let result = reporter.start_with(key, f);
+    reporter.stop();
+    result
+}
+
+/// Combine the times gathered by `reporters` into a new `TimeReporter` named `name`.
+///
+/// The total times and counts are summed as with [`TimeReporter::merge_weighted`],
+/// e.g. to report once for a set of per-thread reporters. The new reporter has the
+/// configuration of the first of `reporters`, if any.
+pub fn combine<S: Into<Cow<'static, str>>>(reporters: &[TimeReporter], name: S) -> TimeReporter {
+    let mut builder = reporters
+        .first()
+        .map_or_else(|| TimeReporterBuilder::new(""), TimeReporter::to_builder);
+    builder.name = name.into();
+    let mut combined = builder.build();
+    for reporter in reporters {
+        combined.merge_weighted(reporter);
+    }
+    combined
+}
+
+impl fmt::Display for TimeReporter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let prefix = &self.config.message_prefix;
+        f.write_str(prefix)?;
+        if self.config.kv_format {
+            return self.fmt_tokens(f, '=', " ");
+        }
+        if self.config.compact {
+            // Keep the tokens unambiguous with a `,` decimal separator
+            let separator = if self.config.decimal_separator == ',' {
+                ";"
+            } else {
+                ","
+            };
+            return self.fmt_tokens(f, ':', separator);
+        }
+        let mut len = prefix.len();
+        let mut separator = if self.config.name_as_field {
+            ""
+        } else {
+            let name = &self.config.name;
+            write!(f, "name: {name}")?;
+            len += "name: ".len() + name.len();
+            ", "
+        };
+        let stats = self.stats();
+        let max = stats.iter().map(|s| s.1).max();
+        if let Some(max_line_len) = self.config.max_line_len {
+            let mut entries = Vec::with_capacity(stats.len());
+            for &(state, dur) in &stats {
+                let mut entry = String::new();
+                self.fmt_stat(&mut entry, separator, state, dur, max)?;
+                entries.push(entry);
+                separator = ", ";
+            }
+            // Room for `…(+N more)` is only reserved if the remaining entries don't all fit
+            let mut rest: usize = entries.iter().map(String::len).sum();
+            for (i, entry) in entries.iter().enumerate() {
+                let more = entries.len() - i;
+                let more_len = if len + rest > max_line_len && more > 1 {
+                    format!(", …(+{} more)", more - 1).len()
+                } else {
+                    0
+                };
+                if len + entry.len() + more_len > max_line_len {
+                    let separator = if i == 0 && self.config.name_as_field {
+                        ""
+                    } else {
+                        ", "
+                    };
+                    write!(f, "{separator}…(+{more} more)")?;
+                    break;
+                }
+                len += entry.len();
+                rest -= entry.len();
+                f.write_str(entry)?;
+            }
+        } else {
+            for &(state, dur) in &stats {
+                self.fmt_stat(f, separator, state, dur, max)?;
+                separator = ", ";
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl Drop for TimeReporter {
     fn drop(&mut self) {
-        let _span = _span!(self.level, "time-report").entered();
-        _event!(target: "tracing-perf", self.level, "{}", self);
+        if self.suppress_report {
+            return;
+        }
+        self.finish_running();
+        if self.config.skip_empty && self.times.is_empty() {
+            return;
+        }
+        self.emit(&*self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Replay `slices` of `(state, millis)` back to back and return the formatted report.
+    fn replay(builder: &mut TimeReporterBuilder, slices: &[(&'static str, u64)]) -> String {
+        let mut reporter = builder.print_order(PrintOrder::Key).width(0).build();
+        let mut at = Instant::now();
+        for &(state, millis) in slices {
+            reporter.start_at(state, at);
+            at += Duration::from_millis(millis);
+        }
+        reporter.stop_at(at);
+        reporter.finish_to_string()
+    }
+
+    #[test]
+    fn rounding_carries_into_seconds() {
+        let dur = Duration::new(1, 999_600_000);
+        assert_eq!(RoundingMode::Up.round(dur, 3), Duration::from_secs(2));
+        assert_eq!(
+            RoundingMode::Down.round(dur, 3),
+            Duration::new(1, 999_000_000)
+        );
+        assert_eq!(RoundingMode::Nearest.round(dur, 3), dur);
+        assert_eq!(
+            RoundingMode::Nearest.round_exact(dur, 3),
+            Duration::from_secs(2)
+        );
+        assert_eq!(
+            RoundingMode::Nearest.round_exact(Duration::new(1, 999_400_000), 3),
+            Duration::new(1, 999_000_000)
+        );
+        assert_eq!(RoundingMode::Up.round(dur, 9), dur);
+        assert_eq!(
+            replay(
+                TimeReporterBuilder::new("r")
+                    .precision(2)
+                    .rounding(RoundingMode::Up),
+                &[("a", 999)]
+            ),
+            "name: r, a: 1.00"
+        );
+    }
+
+    #[test]
+    fn truncated_names_fit_name_width() {
+        let report = replay(
+            TimeReporterBuilder::new("r")
+                .precision(1)
+                .name_width(5)
+                .truncate_names(true),
+            &[("abcdefgh", 100), ("abcde", 100), ("ab", 100)],
+        );
+        assert_eq!(report, "name: r, ab   : 0.1, abcde: 0.1, abcd…: 0.1");
+        let report = replay(
+            TimeReporterBuilder::new("r")
+                .precision(1)
+                .name_width(4)
+                .truncate_names(true)
+                .leader('.'),
+            &[("abcdefgh", 100), ("a", 100)],
+        );
+        assert_eq!(report, "name: r, a...: 0.1, abc…: 0.1");
+    }
+
+    #[test]
+    fn max_line_len_reserves_more_entry() {
+        let slices = [("a", 100), ("b", 200), ("c", 300)];
+        let full = "name: r, a: 0.100, b: 0.200, c: 0.300";
+        let builder = || {
+            let mut builder = TimeReporterBuilder::new("r");
+            builder.precision(3);
+            builder
+        };
+        assert_eq!(replay(&mut builder(), &slices), full);
+        assert_eq!(replay(builder().max_line_len(full.len()), &slices), full);
+        // `c` would fit in the budget, but not once `b` has reserved room for `…(+1 more)`
+        let short = "name: r, a: 0.100, …(+2 more)";
+        assert_eq!(
+            replay(builder().max_line_len(full.len() - 1), &slices),
+            short
+        );
+        assert_eq!(replay(builder().max_line_len(short.len()), &slices), short);
+        assert_eq!(
+            replay(builder().max_line_len(short.len() - 1), &slices),
+            "name: r, …(+3 more)"
+        );
+    }
+
+    #[test]
+    fn natural_order() {
+        use std::cmp::Ordering::{Equal, Greater, Less};
+        assert_eq!(cmp_natural("worker2", "worker10"), Less);
+        assert_eq!(cmp_natural("worker010", "worker9"), Greater);
+        assert_eq!(cmp_natural("a007b", "a7c"), Less);
+        // Equal numbers fall back to the key order
+        assert_eq!(cmp_natural("a01", "a1"), Less);
+        assert_eq!(cmp_natural("a1", "a1"), Equal);
+        // A key ending first comes first
+        assert_eq!(cmp_natural("a1", "a1b"), Less);
+        assert_eq!(cmp_natural("ab", "a"), Greater);
+        assert_eq!(cmp_natural("", "0"), Less);
+    }
+
+    /// Round to milliseconds, as the `minstant` and `quanta` clocks may not add
+    /// durations to instants exactly.
+    fn millis(dur: Duration) -> u128 {
+        (dur + Duration::from_micros(500)).as_millis()
+    }
+
+    #[test]
+    fn auto_stop_counts_idle_slices() {
+        let mut reporter = TimeReporterBuilder::new_ordered("r", PrintOrder::Start)
+            .auto_stop_after(Duration::from_millis(10))
+            .precision(3)
+            .width(0)
+            .build();
+        let ms = Duration::from_millis;
+        let mut at = Instant::now();
+        for slice in [100, 50, 4, 4] {
+            reporter.start_at("a", at);
+            at += ms(slice);
+            assert_eq!(reporter.stop_at(at).map(millis), Some(slice.min(10).into()));
+        }
+        assert_eq!(reporter.duration_for("a").map(millis), Some(28));
+        assert_eq!(reporter.count_for("a"), Some(4));
+        assert_eq!(reporter.max_for("a").map(millis), Some(10));
+        assert_eq!(reporter.stddev_for("a").map(millis), Some(3));
+        assert_eq!(reporter.duration_for("<idle>").map(millis), Some(130));
+        assert_eq!(reporter.count_for("<idle>"), Some(2));
+        assert_eq!(reporter.max_for("<idle>").map(millis), Some(90));
+        assert_eq!(reporter.stddev_for("<idle>").map(millis), Some(25));
+        assert_eq!(reporter.stop_at(at), None);
+        // `<idle>` is only created after the slice it comes from
+        assert_eq!(
+            reporter.finish_to_string(),
+            "name: r, a: 0.028, <idle>: 0.130"
+        );
+    }
+
+    #[test]
+    fn equal_replays_compare_equal() {
+        let start = Instant::now();
+        let replay = || {
+            let mut reporter = TimeReporter::new("r");
+            reporter.start_at("a", start);
+            reporter.start_at("b", start + Duration::from_millis(100));
+            reporter.stop_at(start + Duration::from_millis(150));
+            reporter
+        };
+        let first = replay();
+        std::thread::sleep(Duration::from_millis(20));
+        let second = replay();
+        assert_eq!(first, second);
+        assert_ne!(first, TimeReporter::new("r"));
+    }
+
+    #[test]
+    fn try_stop_returns_counted_slice() {
+        let limit = Duration::from_millis(1);
+        let mut reporter = TimeReporterBuilder::new("r").auto_stop_after(limit).build();
+        assert_eq!(reporter.try_stop(), Err(TimingError::NotRunning));
+        reporter.start("a");
+        std::thread::sleep(limit * 5);
+        assert_eq!(reporter.try_stop(), Ok(limit));
+        assert_eq!(reporter.duration_for("a"), Some(limit));
+    }
+
+    #[test]
+    fn stddev_needs_time_slices() {
+        let ms = Duration::from_millis;
+        let start = Instant::now();
+        let mut reporter = TimeReporter::new("r");
+        reporter.start_at("a", start);
+        reporter.start_at("a", start + ms(100));
+        reporter.stop_at(start + ms(200));
+        let mut weighted = reporter.clone();
+        weighted.merge_weighted(&reporter);
+        assert_eq!(weighted.stddev_for("a").map(millis), Some(0));
+        let mut merged = reporter.clone();
+        merged.merge(&reporter);
+        assert_eq!(merged.stddev_for("a"), None);
+        let mut merged = reporter.clone();
+        merged.merge_max(&reporter);
+        assert_eq!(merged.stddev_for("a"), None);
+        reporter.merge_from_map(&reporter.to_map());
+        assert_eq!(reporter.stddev_for("a"), None);
+    }
+
+    #[test]
+    fn other_bucket_absorbs_other_state() {
+        let report = replay(
+            TimeReporterBuilder::new("r")
+                .other_threshold(Duration::from_millis(50))
+                .precision(2),
+            &[("a", 100), ("<other>", 200), ("b", 10), ("c", 20)],
+        );
+        assert_eq!(report, "name: r, <other>: 0.23, a: 0.10");
+    }
+
+    #[test]
+    fn empty_key_shares_unnamed_state() {
+        let report = replay(
+            TimeReporterBuilder::new("r").precision(1),
+            &[("", 100), ("<unnamed>", 200)],
+        );
+        assert_eq!(report, "name: r, <unnamed>: 0.3");
+    }
+
+    #[test]
+    fn max_at_is_relative_to_creation() {
+        let ms = Duration::from_millis;
+        let mut reporter = TimeReporter::new("r");
+        let start = Instant::now();
+        reporter.start_at("a", start);
+        reporter.start_at("b", start + ms(100));
+        reporter.start_at("a", start + ms(110));
+        reporter.stop_at(start + ms(300));
+        let ended = |key| {
+            let max_at = reporter.max_at_for(key).unwrap();
+            max_at.duration_since(reporter.created_at()).unwrap()
+        };
+        assert!((ms(300)..ms(350)).contains(&ended("a")));
+        assert!((ms(110)..ms(160)).contains(&ended("b")));
+        assert_eq!(reporter.max_at_for("c"), None);
+    }
+
+    #[test]
+    fn clear_resets_max_depth() {
+        let mut reporter = TimeReporter::new("r");
+        {
+            let mut outer = reporter.scope("a");
+            let _inner = outer.scope("b");
+        }
+        assert_eq!(reporter.max_depth(), 2);
+        reporter.clear();
+        assert_eq!(reporter.max_depth(), 0);
+        let mut outer = reporter.scope("a");
+        outer.clear();
+        assert_eq!(outer.max_depth(), 1);
+    }
+
+    #[test]
+    fn report_history_drops_oldest() {
+        let mut history = ReportHistory::new(2);
+        let mut reporter = TimeReporter::new("r");
+        let start = Instant::now();
+        for (i, key) in ["a", "b", "c"].into_iter().enumerate() {
+            reporter.start_at(key, start);
+            reporter.stop_at(start + Duration::from_millis(10));
+            history.record(&reporter);
+            assert_eq!(history.recent().len(), (i + 1).min(2));
+        }
+        let kept: Vec<_> = history.recent().map(|r| r.entries.len()).collect();
+        assert_eq!(kept, [2, 3]);
+    }
+
+    #[test]
+    fn message_prefix_is_part_of_the_report() {
+        let report = replay(
+            TimeReporterBuilder::new("r")
+                .message_prefix("[p] ")
+                .precision(1)
+                .max_line_len(33),
+            &[("a", 100), ("b", 200), ("c", 300)],
+        );
+        // The prefix counts towards the 33 bytes of the line
+        assert_eq!(report, "[p] name: r, a: 0.1, …(+2 more)");
+        let report = replay(
+            TimeReporterBuilder::new("r")
+                .message_prefix("[p] ")
+                .kv_format(true),
+            &[("a", 100)],
+        );
+        assert!(report.starts_with("[p] name=r a="), "{report}");
+    }
+
+    #[test]
+    fn scoped_collector_merges_threads() {
+        let ms = Duration::from_millis;
+        let collector = ScopedCollector::new("r");
+        let start = Instant::now();
+        std::thread::scope(|scope| {
+            for _ in 0..3 {
+                scope.spawn(|| {
+                    let mut handle = collector.handle();
+                    handle.start_at("a", start);
+                    handle.start_at("b", start + ms(100));
+                    handle.stop_at(start + ms(150));
+                });
+            }
+        });
+        let merged = collector.into_inner();
+        assert_eq!(merged.duration_for("a").map(millis), Some(300));
+        assert_eq!(merged.count_for("a"), Some(3));
+        assert_eq!(merged.duration_for("b").map(millis), Some(150));
+        assert_eq!(merged.max_for("b").map(millis), Some(50));
+        assert_eq!(merged.stddev_for("b").map(millis), Some(0));
+    }
+
+    #[test]
+    fn child_time_is_one_parent_slice() {
+        let ms = Duration::from_millis;
+        let mut parent = TimeReporter::new("r");
+        parent.start("load");
+        let start = Instant::now();
+        {
+            let mut child = parent.child("parse");
+            child.breakdown(true);
+            child.start_at("lex", start);
+            child.start_at("tree", start + ms(100));
+            child.stop_at(start + ms(150));
+        }
+        assert_eq!(parent.duration_for("parse").map(millis), Some(150));
+        assert_eq!(parent.count_for("parse"), Some(1));
+        assert_eq!(parent.max_for("parse").map(millis), Some(150));
+        assert_eq!(parent.duration_for("parse::lex").map(millis), Some(100));
+        assert_eq!(parent.duration_for("parse::tree").map(millis), Some(50));
+        parent.stop();
+        assert_eq!(parent.count_for("load"), Some(2));
     }
 }